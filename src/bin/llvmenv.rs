@@ -1,10 +1,10 @@
-use llvmenv::error::CommandExt;
+use llvmenv::error::{CommandExt, FileIoConvert};
 use llvmenv::*;
 
 use simplelog::*;
 use std::{
-    env,
-    path::PathBuf,
+    env, fs,
+    path::{Path, PathBuf},
     process::{exit, Command},
 };
 use structopt::StructOpt;
@@ -34,7 +34,7 @@ enum LLVMEnv {
         #[structopt(
             short = "G",
             long = "builder",
-            help = "Overwrite cmake generator setting"
+            help = "Overwrite cmake generator setting, e.g. Ninja or \"Visual Studio 17 2022\""
         )]
         builder: Option<String>,
         #[structopt(
@@ -51,6 +51,103 @@ enum LLVMEnv {
             help = "Overwrite cmake build type (Debug, Release, RelWithDebInfo, or MinSizeRel)"
         )]
         build_type: Option<entry::BuildType>,
+        #[structopt(
+            long = "host-compiler",
+            help = "Overwrite compiler to build LLVM (system, build:{name}, or path of C compiler)"
+        )]
+        host_compiler: Option<entry::HostCompiler>,
+        #[structopt(
+            long = "compiler-launcher",
+            help = "Overwrite compiler launcher (ccache, sccache, none, or auto)"
+        )]
+        compiler_launcher: Option<entry::CompilerLauncher>,
+        #[structopt(
+            long = "linker",
+            help = "Overwrite linker (lld, gold, mold, system, or auto)"
+        )]
+        linker: Option<entry::Linker>,
+        #[structopt(
+            long = "max-memory",
+            help = "Upper limit of memory to estimate parallel compile/link jobs, e.g. 64G"
+        )]
+        max_memory: Option<String>,
+        #[structopt(
+            long = "name",
+            help = "Name of the build, e.g. my-llvm or {name}-{build_type}"
+        )]
+        install_name: Option<String>,
+        #[structopt(
+            long = "suffix",
+            help = "Suffix appended to the name of the build with a hyphen"
+        )]
+        suffix: Option<String>,
+        #[structopt(long = "force", help = "Build even if the build is up to date")]
+        force: bool,
+        #[structopt(
+            long = "dry-run",
+            help = "Print commands with their environment variables without running them"
+        )]
+        dry_run: bool,
+    },
+
+    #[structopt(
+        name = "test",
+        about = "Run LLVM's test suites (lit check targets) for the build of an entry"
+    )]
+    Test {
+        name: String,
+        #[structopt(
+            long = "suite",
+            number_of_values = 1,
+            default_value = "check-all",
+            help = "Check target to run, e.g. check-llvm or check-clang"
+        )]
+        suites: Vec<String>,
+        #[structopt(short = "j", long = "nproc")]
+        nproc: Option<usize>,
+        #[structopt(
+            short = "t",
+            long = "build-type",
+            help = "Build type of the build to be tested"
+        )]
+        build_type: Option<entry::BuildType>,
+        #[structopt(long = "name", help = "Name of the build to be tested")]
+        install_name: Option<String>,
+        #[structopt(long = "suffix", help = "Suffix of the name of the build to be tested")]
+        suffix: Option<String>,
+    },
+
+    #[structopt(
+        name = "bisect",
+        about = "Find the first bad commit by building and testing each step of git bisect"
+    )]
+    Bisect {
+        name: String,
+        #[structopt(long = "good", help = "Revision known to be good")]
+        good: String,
+        #[structopt(long = "bad", help = "Revision known to be bad")]
+        bad: String,
+        #[structopt(short = "j", long = "nproc")]
+        nproc: Option<usize>,
+        #[structopt(
+            short = "t",
+            long = "build-type",
+            help = "Overwrite cmake build type (Debug, Release, RelWithDebInfo, or MinSizeRel)"
+        )]
+        build_type: Option<entry::BuildType>,
+        #[structopt(
+            last = true,
+            required = true,
+            help = "Test command: exit with 0 for good, 125 for skip, and others in 1-127 for bad"
+        )]
+        command: Vec<String>,
+    },
+
+    #[structopt(name = "logs", about = "Show logs of the latest build of an entry")]
+    Logs {
+        name: String,
+        #[structopt(short = "l", long = "list", help = "List log files of all builds")]
+        list: bool,
     },
 
     #[structopt(name = "current", about = "Show the name of current build")]
@@ -98,6 +195,31 @@ enum LLVMEnv {
         verbose: bool,
     },
 
+    #[structopt(
+        name = "du",
+        about = "Show disk usage of sources, build directories, and builds of entries"
+    )]
+    Du {},
+    #[structopt(
+        name = "gc",
+        about = "Remove build directories, sources, and builds not used recently"
+    )]
+    Gc {
+        #[structopt(
+            long = "keep-builds",
+            help = "Keep installed builds, and remove only build directories and sources"
+        )]
+        keep_builds: bool,
+        #[structopt(
+            long = "older-than",
+            default_value = "30d",
+            help = "Collect builds not used for this duration, e.g. 30d or 12h"
+        )]
+        older_than: String,
+        #[structopt(long = "dry-run", help = "Only show what would be removed")]
+        dry_run: bool,
+    },
+
     #[structopt(name = "edit", about = "Edit llvmenv configure in your editor")]
     Edit {},
 
@@ -105,7 +227,7 @@ enum LLVMEnv {
     Zsh {},
 }
 
-fn main() -> error::Result<()> {
+fn main() {
     TermLogger::init(
         LevelFilter::Info,
         ConfigBuilder::new().set_time_to_local(true).build(),
@@ -117,7 +239,13 @@ fn main() -> error::Result<()> {
     ))
     .unwrap();
 
-    let opt = LLVMEnv::from_args();
+    if let Err(e) = run(LLVMEnv::from_args()) {
+        eprint!("{}", e.report());
+        exit(e.category().exit_code());
+    }
+}
+
+fn run(opt: LLVMEnv) -> error::Result<()> {
     match opt {
         LLVMEnv::Init {} => config::init_config()?,
 
@@ -135,12 +263,8 @@ fn main() -> error::Result<()> {
         }
 
         LLVMEnv::Entries {} => {
-            if let Ok(entries) = entry::load_entries() {
-                for entry in &entries {
-                    println!("{}", entry.name());
-                }
-            } else {
-                panic!("No entries. Please define entries in $XDG_CONFIG_HOME/llvmenv/entry.toml");
+            for entry in &entry::load_entries()? {
+                println!("{}", entry.name());
             }
         }
         LLVMEnv::BuildEntry {
@@ -151,6 +275,14 @@ fn main() -> error::Result<()> {
             builder,
             nproc,
             build_type,
+            host_compiler,
+            compiler_launcher,
+            linker,
+            max_memory,
+            install_name,
+            suffix,
+            force,
+            dry_run,
         } => {
             let mut entry = entry::load_entry(&name)?;
             let nproc = nproc.unwrap_or_else(num_cpus::get);
@@ -160,17 +292,128 @@ fn main() -> error::Result<()> {
             if let Some(build_type) = build_type {
                 entry.set_build_type(build_type)?;
             }
-            if discard {
-                entry.clean_cache_dir().unwrap();
+            if let Some(host_compiler) = host_compiler {
+                entry.set_host_compiler(host_compiler)?;
+            }
+            if let Some(compiler_launcher) = compiler_launcher {
+                entry.set_compiler_launcher(compiler_launcher)?;
+            }
+            if let Some(linker) = linker {
+                entry.set_linker(linker)?;
+            }
+            if let Some(max_memory) = max_memory {
+                entry.set_max_memory(&max_memory)?;
+            }
+            if let Some(install_name) = install_name {
+                entry.set_install_name(&install_name)?;
+            }
+            if let Some(suffix) = suffix {
+                entry.set_install_suffix(&suffix)?;
+            }
+            if !dry_run {
+                if discard {
+                    entry.clean_cache_dir()?;
+                }
+                entry.checkout()?;
+                if update {
+                    entry.update()?;
+                }
+                if clean {
+                    entry.clean_build_dir()?;
+                }
+            }
+            entry.build(&entry::BuildOptions {
+                nproc,
+                dry_run,
+                force,
+            })?;
+        }
+
+        LLVMEnv::Test {
+            name,
+            suites,
+            nproc,
+            build_type,
+            install_name,
+            suffix,
+        } => {
+            let mut entry = entry::load_entry(&name)?;
+            if let Some(build_type) = build_type {
+                entry.set_build_type(build_type)?;
+            }
+            if let Some(install_name) = install_name {
+                entry.set_install_name(&install_name)?;
+            }
+            if let Some(suffix) = suffix {
+                entry.set_install_suffix(&suffix)?;
+            }
+            let summaries = entry.test(&suites, nproc.unwrap_or_else(num_cpus::get))?;
+            for summary in &summaries {
+                println!(
+                    "{}: {} passed, {} failed, {} unsupported",
+                    summary.suite, summary.passed, summary.failed, summary.unsupported
+                );
+                for failure in &summary.failures {
+                    println!("  {}", failure);
+                }
+            }
+            if let Some(summary) = summaries.iter().find(|summary| summary.failed > 0) {
+                return Err(error::Error::TestFailed {
+                    name: entry.install_name()?,
+                    suite: summary.suite.clone(),
+                    failed: summary.failed,
+                });
+            }
+        }
+
+        LLVMEnv::Bisect {
+            name,
+            good,
+            bad,
+            nproc,
+            build_type,
+            command,
+        } => {
+            let mut entry = entry::load_entry(&name)?;
+            if let Some(build_type) = build_type {
+                entry.set_build_type(build_type)?;
+            }
+            entry.checkout()?;
+            let result = bisect::bisect(
+                &entry,
+                &bisect::BisectOptions {
+                    good,
+                    bad,
+                    command,
+                    nproc: nproc.unwrap_or_else(num_cpus::get),
+                },
+            )?;
+            print!("{}", result.summary);
+            if let Some(build) = result.good_build {
+                println!("Build of the last good commit: {}", build);
             }
-            entry.checkout().unwrap();
-            if update {
-                entry.update().unwrap();
+            if let Some(build) = result.bad_build {
+                println!("Build of the first bad commit: {}", build);
             }
-            if clean {
-                entry.clean_build_dir().unwrap();
+        }
+
+        LLVMEnv::Logs { name, list } => {
+            let entry = entry::load_entry(&name)?;
+            let logs = entry.logs()?;
+            if list {
+                for dir in &logs {
+                    for file in log_files(dir)? {
+                        println!("{}", file.display());
+                    }
+                }
+            } else if let Some(dir) = logs.last() {
+                for file in log_files(dir)? {
+                    println!("==> {} <==", file.display());
+                    print!("{}", fs::read_to_string(&file).with(&file)?);
+                }
+            } else {
+                eprintln!("No logs for entry '{}'", name);
             }
-            entry.build(nproc).unwrap();
         }
 
         LLVMEnv::Current { verbose } => {
@@ -183,7 +426,7 @@ fn main() -> error::Result<()> {
             }
         }
         LLVMEnv::Prefix { verbose } => {
-            let build = build::seek_build()?;
+            let build = build::use_build()?;
             println!("{}", build.prefix().display());
             if verbose {
                 if let Some(env) = build.env_path() {
@@ -198,9 +441,9 @@ fn main() -> error::Result<()> {
             patch,
         } => {
             let build = if let Some(name) = name {
-                get_existing_build(&name)
+                get_existing_build(&name)?
             } else {
-                build::seek_build()?
+                build::use_build()?
             };
             let version = build.version()?;
             if !(major || minor || patch) {
@@ -220,25 +463,87 @@ fn main() -> error::Result<()> {
         }
 
         LLVMEnv::Global { name } => {
-            let build = get_existing_build(&name);
+            let build = get_existing_build(&name)?;
             build.set_global()?;
         }
         LLVMEnv::Local { name, path } => {
-            let build = get_existing_build(&name);
-            let path = path.unwrap_or_else(|| env::current_dir().unwrap());
+            let build = get_existing_build(&name)?;
+            let path = match path {
+                Some(path) => path,
+                None => env::current_dir()?,
+            };
             build.set_local(&path)?;
         }
 
         LLVMEnv::Archive { name, verbose } => {
-            let build = get_existing_build(&name);
+            let build = get_existing_build(&name)?;
             build.archive(verbose)?;
         }
         LLVMEnv::Expand { path, verbose } => {
             build::expand(&path, verbose)?;
         }
 
+        LLVMEnv::Du {} => {
+            let usages = gc::disk_usage()?;
+            let width = usages
+                .iter()
+                .map(|usage| usage.entry.len())
+                .chain(Some("ENTRY".len()))
+                .max()
+                .unwrap();
+            println!(
+                "{:<width$} {:>8} {:>8} {:>8} {:>8}",
+                "ENTRY",
+                "SOURCE",
+                "BUILD",
+                "MIRROR",
+                "INSTALL",
+                width = width
+            );
+            for usage in &usages {
+                println!(
+                    "{:<width$} {:>8} {:>8} {:>8} {:>8}",
+                    usage.entry,
+                    gc::format_size(usage.source),
+                    gc::format_size(usage.build),
+                    gc::format_size(usage.mirror),
+                    gc::format_size(usage.install),
+                    width = width
+                );
+            }
+            for dir in usages
+                .iter()
+                .filter_map(|usage| usage.legacy_build_dir.as_ref())
+            {
+                eprintln!(
+                    "{} is a build directory of old llvmenv, and not used anymore",
+                    dir.display()
+                );
+            }
+        }
+        LLVMEnv::Gc {
+            keep_builds,
+            older_than,
+            dry_run,
+        } => {
+            let removed = gc::gc(&gc::GcOptions {
+                keep_builds,
+                older_than: gc::parse_duration(&older_than)?,
+                dry_run,
+            })?;
+            for (path, size) in &removed {
+                println!("{:>8} {}", gc::format_size(*size), path.display());
+            }
+            let total = removed.iter().map(|(_, size)| size).sum();
+            if dry_run {
+                println!("{} would be freed", gc::format_size(total));
+            } else {
+                println!("{} freed", gc::format_size(total));
+            }
+        }
+
         LLVMEnv::Edit {} => {
-            let editor = env::var("EDITOR").expect("EDITOR environmental value is not set");
+            let editor = env::var("EDITOR").map_err(|_| error::Error::EditorNotSet)?;
             Command::new(editor)
                 .arg(config::config_dir()?.join(config::ENTRY_TOML))
                 .check_run()?;
@@ -252,12 +557,20 @@ fn main() -> error::Result<()> {
     Ok(())
 }
 
-fn get_existing_build(name: &str) -> build::Build {
-    let build = build::Build::from_name(name).unwrap();
+fn log_files(dir: &Path) -> error::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).with(dir)? {
+        files.push(entry.with(dir)?.path());
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn get_existing_build(name: &str) -> error::Result<build::Build> {
+    let build = build::Build::from_name(name)?;
     if build.exists() {
-        build
+        Ok(build)
     } else {
-        eprintln!("Build '{}' does not exists", name);
-        exit(1)
+        Err(error::Error::BuildNotFound { name: name.into() })
     }
 }
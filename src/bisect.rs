@@ -0,0 +1,471 @@
+//! Find the first bad commit of LLVM by building and testing each step of `git bisect`
+//!
+//! ```shell
+//! llvmenv bisect llvm-dev --good llvmorg-13.0.0 --bad main -- ./reproduce.sh
+//! ```
+//!
+//! `git bisect` runs in the source directory of a remote entry, or in a temporary worktree `{install_name}-bisect-src`
+//! next to the build directories for a local entry, whose own tree is never modified.
+//! The whole history is fetched first if the source of a remote entry is a shallow clone,
+//! while a shallow local source is an error, since its history is fetched only by the user.
+//! Each step is built into the build `{install_name}-bisect-step`,
+//! while all steps share the build directory `{install_name}-bisect` to be built incrementally.
+//!
+//! The test command runs in the current directory with `bin` of the step's build at the head of `PATH`,
+//! and its exit code is interpreted in the same way as `git bisect run`:
+//!
+//! | Exit code         | Commit                     |
+//! |:------------------|:---------------------------|
+//! | 0                 | good                       |
+//! | 125               | skipped (cannot be tested) |
+//! | 1-127 except 125  | bad                        |
+//! | others            | bisect is aborted          |
+//!
+//! Commits which fail to build are skipped. Builds of the last good commit and the first bad commit are kept
+//! as `{install_name}-bisect-good` and `{install_name}-bisect-bad`, and reused by later bisects,
+//! while the build of each step is replaced by the next step. Thus no more builds are left even if bisect is interrupted.
+
+use log::{info, warn};
+use std::{
+    env, fs, iter,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    build::Build,
+    entry::{build_root, BuildOptions, Entry},
+    error::*,
+};
+
+/// Result of testing a commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Good,
+    Bad,
+    Skip,
+}
+
+impl Verdict {
+    /// Interpret the exit code of a test command like `git bisect run`. `None` means to abort.
+    pub fn from_exit_code(code: Option<i32>) -> Option<Self> {
+        match code? {
+            0 => Some(Verdict::Good),
+            125 => Some(Verdict::Skip),
+            1..=127 => Some(Verdict::Bad),
+            _ => None,
+        }
+    }
+
+    fn subcommand(self) -> &'static str {
+        match self {
+            Verdict::Good => "good",
+            Verdict::Bad => "bad",
+            Verdict::Skip => "skip",
+        }
+    }
+}
+
+/// Options of [bisect]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BisectOptions {
+    /// Revision known to be good
+    pub good: String,
+    /// Revision known to be bad
+    pub bad: String,
+    /// Test command and its arguments
+    pub command: Vec<String>,
+    /// Number of parallel jobs to build each step
+    pub nproc: usize,
+}
+
+/// Result of [bisect]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BisectResult {
+    /// SHA of the first bad commit
+    pub commit: String,
+    /// Description of the first bad commit reported by `git bisect`
+    pub summary: String,
+    /// Build of the last good commit
+    pub good_build: Option<String>,
+    /// Build of the first bad commit
+    pub bad_build: Option<String>,
+}
+
+/// Builds installed by bisect: the build of the current step, and the builds kept for the last good and bad commits
+struct Builds {
+    step: String,
+    good: String,
+    bad: String,
+}
+
+impl Builds {
+    fn new(entry: &Entry) -> Result<Self> {
+        let name = |suffix: &str| -> Result<String> {
+            Ok(format!("{}-bisect-{}", entry.install_name()?, suffix))
+        };
+        Ok(Builds {
+            step: name("step")?,
+            good: name("good")?,
+            bad: name("bad")?,
+        })
+    }
+
+    /// Build of the commit if it is kept
+    fn find(&self, commit: &str) -> Result<Option<&str>> {
+        for name in [&self.good, &self.bad] {
+            if let Some(metadata) = Build::from_name(name)?.metadata()? {
+                if metadata.revision.as_deref() == Some(commit) {
+                    return Ok(Some(name));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Keep the tested build as the build of the verdict, or remove it if skipped
+    fn keep(&self, name: &str, verdict: Verdict) -> Result<()> {
+        let target = match verdict {
+            Verdict::Good => &self.good,
+            Verdict::Bad => &self.bad,
+            Verdict::Skip if name == self.step => return remove_build(name),
+            Verdict::Skip => return Ok(()),
+        };
+        if name == target {
+            return Ok(());
+        }
+        remove_build(target)?;
+        let (from, to) = (Build::from_name(name)?, Build::from_name(target)?);
+        fs::rename(from.prefix(), to.prefix()).with(from.prefix())
+    }
+}
+
+fn remove_build(name: &str) -> Result<()> {
+    let build = Build::from_name(name)?;
+    if build.exists() {
+        info!("Remove build: {}", name);
+        fs::remove_dir_all(build.prefix()).with(build.prefix())?;
+    }
+    Ok(())
+}
+
+/// Temporary worktree of a local source, removed when dropped
+struct Worktree {
+    repo: PathBuf,
+    path: PathBuf,
+}
+
+impl Worktree {
+    fn add(repo: &Path, path: &Path) -> Result<Self> {
+        let worktree = Worktree {
+            repo: repo.into(),
+            path: path.into(),
+        };
+        // Left by an interrupted bisect
+        worktree.remove();
+        info!("Create worktree for bisect: {}", path.display());
+        Command::new("git")
+            .args(["worktree", "add", "-q", "--detach"])
+            .arg(path)
+            .arg("HEAD")
+            .current_dir(repo)
+            .check_run()?;
+        Ok(worktree)
+    }
+
+    fn remove(&self) {
+        if self.path.exists() {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+        let _ = Command::new("git")
+            .args(["worktree", "prune"])
+            .current_dir(&self.repo)
+            .silent()
+            .check_run();
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+/// Bisect the source of the entry between `good` and `bad` revisions
+pub fn bisect(entry: &Entry, opts: &BisectOptions) -> Result<BisectResult> {
+    let src_dir = entry.src_dir()?;
+    let invalid = |message: &str| Error::InvalidEntry {
+        name: entry.name().into(),
+        message: message.into(),
+    };
+    if opts.command.is_empty() {
+        return Err(invalid("Test command for bisect is not specified"));
+    }
+    if !src_dir.join(".git").exists() {
+        return Err(invalid("Source is not a git repository"));
+    }
+    entry.unshallow()?;
+
+    // Local source is bisected in a worktree not to touch the tree of the user
+    let worktree_dir = bisect_src_dir(entry)?;
+    let mut entry = entry.clone();
+    let _worktree = match &mut entry {
+        Entry::Local { path, .. } => {
+            let worktree = Worktree::add(&src_dir, &worktree_dir)?;
+            *path = worktree.path.clone();
+            Some(worktree)
+        }
+        Entry::Remote { .. } => None,
+    };
+    let entry = &entry;
+    let src_dir = entry.src_dir()?;
+
+    let build_dir = bisect_build_dir(entry)?;
+    let builds = Builds::new(entry)?;
+    let mut last_good = None;
+    let mut last_bad = None;
+    entry.forget_patches("")?;
+    let bisected = run_bisect(&src_dir, entry.name(), &opts.good, &opts.bad, |commit| {
+        entry.apply_patches()?;
+        let verdict = test_commit(entry, commit, &build_dir, &builds, opts);
+        entry.forget_patches("")?;
+        let verdict = verdict?;
+        match verdict {
+            Verdict::Good => last_good = Some(commit.to_string()),
+            Verdict::Bad => last_bad = Some(commit.to_string()),
+            Verdict::Skip => {}
+        }
+        Ok(verdict)
+    });
+    entry.apply_patches()?;
+    remove_build(&builds.step)?;
+    let (commit, summary) = bisected?;
+
+    // The first bad commit is the last one tested bad, unless it is `bad` itself
+    let good_build = last_good.map(|_| builds.good.clone());
+    let bad_build = Some(builds.bad.clone()).filter(|_| last_bad.as_ref() == Some(&commit));
+    Ok(BisectResult {
+        commit,
+        summary,
+        good_build,
+        bad_build,
+    })
+}
+
+/// Build directory shared by the steps of bisect
+pub(crate) fn bisect_build_dir(entry: &Entry) -> Result<PathBuf> {
+    Ok(build_root()?.join(format!("{}-bisect", entry.install_name()?)))
+}
+
+/// Temporary worktree of the local source for bisect
+pub(crate) fn bisect_src_dir(entry: &Entry) -> Result<PathBuf> {
+    Ok(build_root()?.join(format!("{}-bisect-src", entry.install_name()?)))
+}
+
+/// Build the commit checked out in the source (or reuse the kept build), and run the test command with it
+fn test_commit(
+    entry: &Entry,
+    commit: &str,
+    build_dir: &Path,
+    builds: &Builds,
+    opts: &BisectOptions,
+) -> Result<Verdict> {
+    let name = match builds.find(commit)? {
+        Some(name) => {
+            info!("Reuse build {} for {}", name, commit);
+            name
+        }
+        None => {
+            let mut entry = entry.clone();
+            entry.set_install_name(&builds.step)?;
+            entry.set_build_dir(build_dir)?;
+            let built = entry.build(&BuildOptions {
+                nproc: opts.nproc,
+                dry_run: false,
+                force: false,
+            });
+            match built {
+                Ok(()) => {}
+                Err(e @ Error::LoggedCommandError { .. }) => {
+                    warn!("Skip {} since its build failed: {}", commit, e);
+                    builds.keep(&builds.step, Verdict::Skip)?;
+                    return Ok(Verdict::Skip);
+                }
+                Err(e) => return Err(e),
+            }
+            &builds.step
+        }
+    };
+    let build = Build::from_name(name)?;
+
+    let paths = env::var_os("PATH").unwrap_or_default();
+    let path =
+        env::join_paths(iter::once(build.prefix().join("bin")).chain(env::split_paths(&paths)))
+            .map_err(|e| Error::InvalidEntry {
+                name: entry.name().into(),
+                message: e.to_string(),
+            })?;
+    let mut command = Command::new(&opts.command[0]);
+    command.args(&opts.command[1..]).env("PATH", path);
+    let status = command.status().map_err(|_| Error::CommandNotFound {
+        cmd: format!("{:?}", command),
+    })?;
+    let verdict = Verdict::from_exit_code(status.code()).ok_or_else(|| Error::BisectFailed {
+        entry: entry.name().into(),
+        message: format!("Test command {} at {}", status, commit),
+    })?;
+    info!("{} is {}", commit, verdict.subcommand());
+    builds.keep(name, verdict)?;
+    Ok(verdict)
+}
+
+/// Drive `git bisect` in `dir`. `step` tests the commit checked out, and modifications of the source are discarded after it.
+/// Returns the first bad commit with its description.
+fn run_bisect(
+    dir: &Path,
+    name: &str,
+    good: &str,
+    bad: &str,
+    mut step: impl FnMut(&str) -> Result<Verdict>,
+) -> Result<(String, String)> {
+    let git = |args: &[&str]| -> Result<String> {
+        Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .check_output()
+            .map(|(stdout, _)| stdout)
+    };
+    let bisect = |args: &[&str]| -> Result<String> {
+        git(&["reset", "-q", "--hard"])?;
+        let mut command = Command::new("git");
+        command.arg("bisect").args(args).current_dir(dir);
+        let output = command.output().map_err(|_| Error::CommandNotFound {
+            cmd: format!("{:?}", command),
+        })?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if stdout.contains("only 'skip'ped commits left") || !output.status.success() {
+            return Err(Error::BisectFailed {
+                entry: name.into(),
+                message: format!("{}{}", stdout, stderr).trim().to_string(),
+            });
+        }
+        Ok(stdout)
+    };
+
+    let result = (|| {
+        let mut output = bisect(&["start", bad, good])?;
+        loop {
+            if let Some(commit) = first_bad_commit(&output) {
+                return Ok((commit, output));
+            }
+            let commit = git(&["rev-parse", "HEAD"])?.trim().to_string();
+            let verdict = step(&commit)?;
+            output = bisect(&[verdict.subcommand(), &commit])?;
+        }
+    })();
+    git(&["reset", "-q", "--hard"])?;
+    git(&["bisect", "reset"])?;
+    result
+}
+
+/// Parse the first bad commit from the output of `git bisect`
+fn first_bad_commit(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        line.strip_suffix(" is the first bad commit")
+            .map(|commit| commit.trim().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resource::test_git;
+    use tempfile::TempDir;
+
+    #[test]
+    fn exit_code() {
+        assert_eq!(Verdict::from_exit_code(Some(0)), Some(Verdict::Good));
+        assert_eq!(Verdict::from_exit_code(Some(1)), Some(Verdict::Bad));
+        assert_eq!(Verdict::from_exit_code(Some(125)), Some(Verdict::Skip));
+        assert_eq!(Verdict::from_exit_code(Some(127)), Some(Verdict::Bad));
+        assert_eq!(Verdict::from_exit_code(Some(128)), None);
+        assert_eq!(Verdict::from_exit_code(Some(-1)), None);
+        assert_eq!(Verdict::from_exit_code(None), None);
+    }
+
+    #[test]
+    fn bisect_commits() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let dir = tmp.path();
+        let git = |args: &[&str]| test_git(dir, args);
+        git(&["init", "-q"])?;
+        let mut commits = Vec::new();
+        for i in 0..10 {
+            fs::write(dir.join("version"), i.to_string())?;
+            git(&["add", "."])?;
+            git(&["commit", "-q", "-m", &format!("commit {}", i)])?;
+            commits.push(git(&["rev-parse", "HEAD"])?);
+        }
+        let head = git(&["rev-parse", "HEAD"])?;
+        let version = || fs::read_to_string(dir.join("version")).unwrap();
+
+        // Broken at commit 6, and commit 3 cannot be tested
+        let (commit, summary) = run_bisect(dir, "test", &commits[0], &commits[9], |_| {
+            // Modification is discarded
+            let v: usize = version().parse().unwrap();
+            fs::write(dir.join("version"), "modified").unwrap();
+            Ok(match v {
+                3 => Verdict::Skip,
+                v if v >= 6 => Verdict::Bad,
+                _ => Verdict::Good,
+            })
+        })?;
+        assert_eq!(commit, commits[6]);
+        assert!(summary.contains("commit 6"));
+        // Source is restored
+        assert_eq!(git(&["rev-parse", "HEAD"])?, head);
+        assert_eq!(version(), "9");
+
+        // Only skipped commits are left
+        let err = run_bisect(dir, "test", &commits[4], &commits[6], |_| Ok(Verdict::Skip));
+        match err {
+            Err(Error::BisectFailed { message, .. }) => assert!(message.contains("skip")),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(git(&["rev-parse", "HEAD"])?, head);
+        Ok(())
+    }
+
+    #[test]
+    fn worktree() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let repo = tmp.path().join("llvm");
+        fs::create_dir(&repo)?;
+        let git = |args: &[&str]| test_git(&repo, args);
+        git(&["init", "-q"])?;
+        fs::write(repo.join("version"), "1")?;
+        git(&["add", "."])?;
+        git(&["commit", "-q", "-m", "init"])?;
+
+        let path = tmp.path().join("llvm-bisect-src");
+        // Directory left by an interrupted bisect is replaced
+        fs::create_dir(&path)?;
+        let worktree = Worktree::add(&repo, &path)?;
+        assert_eq!(fs::read_to_string(path.join("version"))?, "1");
+        drop(worktree);
+        assert!(!path.exists());
+        assert_eq!(git(&["worktree", "list"])?.lines().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_first_bad_commit() {
+        let output = "0123abcd is the first bad commit\ncommit 0123abcd\nAuthor: llvmenv\n";
+        assert_eq!(first_bad_commit(output).as_deref(), Some("0123abcd"));
+        assert_eq!(
+            first_bad_commit("Bisecting: 3 revisions left to test after this (roughly 2 steps)"),
+            None
+        );
+    }
+}
@@ -4,17 +4,156 @@ use glob::glob;
 use log::*;
 use regex::Regex;
 use semver::Version;
+use serde_derive::{Deserialize, Serialize};
 use std::{
     env, fs,
     io::{self, Read, Write},
     path::{Path, PathBuf},
     process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::config::*;
+use crate::entry::{AppliedPatch, CompilerLauncher, Linker};
 use crate::error::*;
 
-const LLVMENV_FN: &str = ".llvmenv";
+pub(crate) const LLVMENV_FN: &str = ".llvmenv";
+pub(crate) const METADATA_FN: &str = ".llvmenv-build.toml";
+/// Last time when the build is used, in seconds since the UNIX epoch, stored in the prefix
+const LAST_USED_FN: &str = ".llvmenv-last-used";
+/// Directories where `.llvmenv` has been written by `llvmenv local`, one per line in the config directory
+const LOCAL_ENVS_FN: &str = "local-envs.txt";
+
+/// Metadata of a build installed by `llvmenv build-entry`, stored in the prefix
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BuildMetadata {
+    /// Name of entry
+    pub entry: String,
+    /// Triple of the host where the build runs, set for cross-compiled builds
+    pub host_triple: Option<String>,
+    /// Compiler launcher used in the build
+    pub compiler_launcher: Option<CompilerLauncher>,
+    /// Linker used in the build
+    pub linker: Option<Linker>,
+    /// Revision of the source, e.g. the commit SHA of git repository
+    pub revision: Option<String>,
+    /// Patches applied to the source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<AppliedPatch>,
+    /// Results of LLVM's test suites run by `llvmenv test`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<TestSummary>,
+}
+
+impl BuildMetadata {
+    pub fn write(&self, prefix: &Path) -> Result<()> {
+        let path = prefix.join(METADATA_FN);
+        let content = toml::to_string(self)
+            .map_err(io::Error::other)
+            .with(&path)?;
+        fs::write(&path, content).with(&path)?;
+        Ok(())
+    }
+}
+
+/// Summary of a lit test suite (a check target, e.g. `check-clang`) run for the build
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TestSummary {
+    /// Check target
+    pub suite: String,
+    /// Date and time when the suite run
+    pub date: String,
+    /// Number of passed tests, including expectedly failed ones
+    pub passed: usize,
+    /// Number of failed tests, including unexpectedly passed, unresolved, and timed out ones
+    pub failed: usize,
+    /// Number of unsupported or skipped tests
+    pub unsupported: usize,
+    /// Names of failed tests
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct LitResults {
+    tests: Vec<LitTest>,
+}
+
+#[derive(Deserialize)]
+struct LitTest {
+    name: String,
+    code: String,
+}
+
+impl TestSummary {
+    /// Summarize the results written by `lit --output`
+    pub fn from_lit_json(suite: &str, json: &str) -> serde_json::Result<Self> {
+        let results: LitResults = serde_json::from_str(json)?;
+        let mut summary = TestSummary {
+            suite: suite.into(),
+            date: chrono::Local::now().to_rfc3339(),
+            ..Default::default()
+        };
+        for test in results.tests {
+            match test.code.as_str() {
+                "PASS" | "FLAKYPASS" | "XFAIL" => summary.passed += 1,
+                "UNSUPPORTED" | "SKIPPED" | "EXCLUDED" => summary.unsupported += 1,
+                _ => {
+                    summary.failed += 1;
+                    summary.failures.push(test.name);
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Contents of `.llvmenv` file
+///
+/// `.llvmenv` is a plain text containing the name of build, or a TOML file
+/// which can also define project-local entries:
+///
+/// ```toml
+/// build = "my-llvm"
+///
+/// [entries.my-llvm]
+/// inherits = "13.0.0"
+/// target = ["X86"]
+/// ```
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct LocalEnv {
+    pub build: Option<String>,
+    #[serde(default)]
+    pub entries: toml::value::Table,
+}
+
+impl LocalEnv {
+    /// Parse the content. A single line without `=` nor `[` is regarded as the name of build.
+    fn parse(s: &str) -> std::result::Result<Self, toml::de::Error> {
+        let name = s.trim();
+        if name.is_empty() || name.contains(['\n', '=', '[']) {
+            return toml::from_str(s);
+        }
+        Ok(LocalEnv {
+            build: Some(name.into()),
+            entries: Default::default(),
+        })
+    }
+}
+
+/// Read `.llvmenv` in the directory if exists
+pub(crate) fn read_local_env(dir: &Path) -> Result<Option<LocalEnv>> {
+    let cand = dir.join(LLVMENV_FN);
+    if !cand.exists() {
+        return Ok(None);
+    }
+    let mut f = fs::File::open(&cand).with(&cand)?;
+    let mut s = String::new();
+    f.read_to_string(&mut s).with(&cand)?;
+    let local =
+        LocalEnv::parse(&s).map_err(|source| Error::InvalidLocalEnv { path: cand, source })?;
+    Ok(Some(local))
+}
 
 #[derive(Debug)]
 pub struct Build {
@@ -64,6 +203,45 @@ impl Build {
         &self.prefix
     }
 
+    /// Metadata of the build. `None` if the build is not installed by llvmenv (e.g. system)
+    pub fn metadata(&self) -> Result<Option<BuildMetadata>> {
+        let path = self.prefix.join(METADATA_FN);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(toml::from_str(
+            &fs::read_to_string(&path).with(&path)?,
+        )?))
+    }
+
+    /// Record that the build is used now. Only builds installed by `llvmenv build-entry` are recorded.
+    pub fn record_use(&self) -> Result<()> {
+        if !self.prefix.join(METADATA_FN).exists() {
+            return Ok(());
+        }
+        let path = self.prefix.join(LAST_USED_FN);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        fs::write(&path, now.to_string()).with(&path)?;
+        Ok(())
+    }
+
+    /// Last time recorded by [Build::record_use]. `None` if never recorded, e.g. installed by old llvmenv
+    pub fn last_used(&self) -> Result<Option<SystemTime>> {
+        let path = self.prefix.join(LAST_USED_FN);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with(&path),
+        };
+        Ok(content
+            .trim()
+            .parse()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+
     pub fn env_path(&self) -> Option<&Path> {
         match self.llvmenv {
             Some(ref path) => Some(path.as_path()),
@@ -72,15 +250,30 @@ impl Build {
     }
 
     pub fn set_global(&self) -> Result<()> {
-        self.set_local(&config_dir()?)
+        self.write_local_env(&config_dir()?)
     }
 
     pub fn set_local(&self, path: &Path) -> Result<()> {
+        self.write_local_env(path)?;
+        register_local_env(path)
+    }
+
+    fn write_local_env(&self, path: &Path) -> Result<()> {
         let env = path.join(LLVMENV_FN);
+        let content = match read_local_env(path)? {
+            // Keep project-local entries
+            Some(local) if !local.entries.is_empty() => toml::to_string(&LocalEnv {
+                build: Some(self.name.clone()),
+                ..local
+            })
+            .map_err(io::Error::other)
+            .with(&env)?,
+            _ => self.name.clone(),
+        };
         let mut f = fs::File::create(&env).with(&env)?;
-        write!(f, "{}", self.name).with(env)?;
+        write!(f, "{}", content).with(&env)?;
         info!("Write setting to {}", path.display());
-        Ok(())
+        self.record_use()
     }
 
     pub fn archive(&self, verbose: bool) -> Result<()> {
@@ -118,7 +311,7 @@ fn local_builds() -> Result<Vec<Build>> {
         .unwrap()
         .filter_map(|path| {
             if let Ok(path) = path {
-                path.parent().map(|path| Build::from_path(path))
+                path.parent().map(Build::from_path)
             } else {
                 None
             }
@@ -133,16 +326,71 @@ pub fn builds() -> Result<Vec<Build>> {
     Ok(bs)
 }
 
-fn load_local_env(path: &Path) -> Result<Option<Build>> {
-    let cand = path.join(LLVMENV_FN);
-    if !cand.exists() {
+/// Remember the directory of `.llvmenv` not to remove its build by `llvmenv gc`
+fn register_local_env(dir: &Path) -> Result<()> {
+    let dir = dir.canonicalize().with(dir)?;
+    let mut dirs = registered_local_envs()?.unwrap_or_default();
+    if !dirs.contains(&dir) {
+        dirs.push(dir);
+    }
+    write_local_envs(&dirs)
+}
+
+fn write_local_envs(dirs: &[PathBuf]) -> Result<()> {
+    let registry = config_dir()?.join(LOCAL_ENVS_FN);
+    let mut f = fs::File::create(&registry).with(&registry)?;
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        writeln!(f, "{}", dir.display()).with(&registry)?;
+    }
+    Ok(())
+}
+
+/// Directories where `.llvmenv` has been written by `llvmenv local`.
+/// `None` if `llvmenv local` has never been run, i.e. `.llvmenv` files written by old llvmenv are unknown.
+pub fn registered_local_envs() -> Result<Option<Vec<PathBuf>>> {
+    let registry = config_dir()?.join(LOCAL_ENVS_FN);
+    if !registry.exists() {
         return Ok(None);
     }
-    let mut f = fs::File::open(&cand).with(&cand)?;
-    let mut s = String::new();
-    f.read_to_string(&mut s).with(cand)?;
-    let name = s.trim();
-    let mut build = Build::from_name(name)?;
+    Ok(Some(
+        fs::read_to_string(&registry)
+            .with(&registry)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    ))
+}
+
+/// Forget the directories of `.llvmenv` which no longer exist
+pub fn prune_local_envs() -> Result<()> {
+    match registered_local_envs()? {
+        Some(dirs) => write_local_envs(&dirs),
+        None => Ok(()),
+    }
+}
+
+/// Builds used by the global `.llvmenv`, `.llvmenv` files written by `llvmenv local`,
+/// and `.llvmenv` files in the current directory and its ancestors, with the paths of `.llvmenv`
+pub fn referenced_builds() -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = vec![config_dir()?];
+    dirs.extend(registered_local_envs()?.unwrap_or_default());
+    dirs.extend(env::current_dir()?.ancestors().map(PathBuf::from));
+    let mut builds = Vec::new();
+    for dir in dirs {
+        if let Some(name) = read_local_env(&dir)?.and_then(|local| local.build) {
+            builds.push((name, dir.join(LLVMENV_FN)));
+        }
+    }
+    Ok(builds)
+}
+
+fn load_local_env(path: &Path) -> Result<Option<Build>> {
+    let name = match read_local_env(path)?.and_then(|local| local.build) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let mut build = Build::from_name(&name)?;
     if build.exists() {
         build.llvmenv = Some(path.into());
         Ok(Some(build))
@@ -157,7 +405,7 @@ fn load_global_env() -> Result<Option<Build>> {
 
 pub fn seek_build() -> Result<Build> {
     // Seek .llvmenv from $PWD
-    let mut path = env::current_dir().unwrap();
+    let mut path = env::current_dir()?;
     loop {
         if let Some(mut build) = load_local_env(&path)? {
             build.llvmenv = Some(path.join(LLVMENV_FN));
@@ -176,6 +424,22 @@ pub fn seek_build() -> Result<Build> {
     Ok(Build::system())
 }
 
+/// [seek_build] recording the use of the build for `llvmenv gc`.
+/// The record is updated at most once an hour since `llvmenv prefix` runs at every prompt with the Zsh integration.
+pub fn use_build() -> Result<Build> {
+    let build = seek_build()?;
+    let recent = build
+        .last_used()?
+        .and_then(|last_used| last_used.elapsed().ok())
+        .is_some_and(|elapsed| elapsed < Duration::from_secs(60 * 60));
+    if !recent {
+        if let Err(e) = build.record_use() {
+            warn!("Cannot record the use of {}: {}", build.name(), e);
+        }
+    }
+    Ok(build)
+}
+
 pub fn expand(archive: &Path, verbose: bool) -> Result<()> {
     if !archive.exists() {
         return Err(io::Error::new(
@@ -219,4 +483,91 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_local_env() -> Result<()> {
+        let local = LocalEnv::parse("10.0.0\n")?;
+        assert_eq!(local.build.as_deref(), Some("10.0.0"));
+        assert!(local.entries.is_empty());
+
+        let local = LocalEnv::parse(
+            r#"
+            build = "my-llvm"
+            [entries.my-llvm]
+            inherits = "13.0.0"
+            "#,
+        )?;
+        assert_eq!(local.build.as_deref(), Some("my-llvm"));
+        assert!(local.entries.contains_key("my-llvm"));
+        assert_eq!(LocalEnv::parse("")?.build, None);
+
+        // Malformed TOML is not regarded as a build name
+        assert!(LocalEnv::parse("build = my-llvm").is_err());
+        assert!(LocalEnv::parse("[entries.my-llvm\ninherits = \"13.0.0\"").is_err());
+
+        let dir = tempfile::TempDir::new()?;
+        fs::write(dir.path().join(LLVMENV_FN), "build = \"my-llvm\"\n[entries").with(dir.path())?;
+        assert!(matches!(
+            read_local_env(dir.path()),
+            Err(Error::InvalidLocalEnv { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() -> Result<()> {
+        let prefix = tempfile::TempDir::new()?;
+        let metadata = BuildMetadata {
+            entry: "my-llvm".into(),
+            linker: Some(Linker::Lld),
+            revision: Some("0123456789abcdef0123456789abcdef01234567".into()),
+            patches: vec![AppliedPatch {
+                patch: "fix.patch".into(),
+                dir: "tools/clang".into(),
+                sha256: "0".repeat(64),
+            }],
+            ..Default::default()
+        };
+        metadata.write(prefix.path())?;
+        let build = Build {
+            name: "my-llvm".into(),
+            prefix: prefix.path().into(),
+            llvmenv: None,
+        };
+        assert_eq!(build.metadata()?, Some(metadata));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lit_summary() -> Result<()> {
+        let json = r#"{
+            "__version__": [17, 0, 0],
+            "elapsed": 1.5,
+            "tests": [
+                {"name": "Clang :: a.c", "code": "PASS", "elapsed": 0.1, "output": ""},
+                {"name": "Clang :: b.c", "code": "XFAIL", "elapsed": 0.1, "output": ""},
+                {"name": "Clang :: c.c", "code": "FAIL", "elapsed": 0.1, "output": "error"},
+                {"name": "Clang :: d.c", "code": "UNSUPPORTED", "elapsed": 0.0, "output": ""},
+                {"name": "Clang :: e.c", "code": "XPASS", "elapsed": 0.1, "output": ""}
+            ]
+        }"#;
+        let summary = TestSummary::from_lit_json("check-clang", json).unwrap();
+        assert_eq!(summary.suite, "check-clang");
+        assert_eq!(
+            (summary.passed, summary.failed, summary.unsupported),
+            (2, 2, 1)
+        );
+        assert_eq!(summary.failures, vec!["Clang :: c.c", "Clang :: e.c"]);
+
+        // Stored with the metadata
+        let prefix = tempfile::TempDir::new()?;
+        let metadata = BuildMetadata {
+            entry: "my-llvm".into(),
+            tests: vec![summary],
+            ..Default::default()
+        };
+        metadata.write(prefix.path())?;
+        assert_eq!(Build::from_path(prefix.path()).metadata()?, Some(metadata));
+        Ok(())
+    }
 }
@@ -1,12 +1,20 @@
 use log::info;
+use serde_derive::Deserialize;
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 
+use crate::entry::{CompilerLauncher, Linker};
 use crate::error::*;
 
 pub const APP_NAME: &str = "llvmenv";
 pub const ENTRY_TOML: &str = "entry.toml";
+pub const PROJECT_TOML: &str = "llvmenv.toml";
+pub const CONFIG_TOML: &str = "config.toml";
+/// Environment variable to change the root of build directories
+pub const BUILD_ROOT_ENV: &str = "LLVMENV_BUILD_ROOT";
+/// Directory of git mirrors shared by entries in the cache directory
+pub const GIT_MIRROR_DIR: &str = "git";
 
 const LLVM_MIRROR: &str = include_str!("llvm-mirror.toml");
 
@@ -38,6 +46,31 @@ pub fn data_dir() -> Result<PathBuf> {
     Ok(path)
 }
 
+/// Global setting of llvmenv in `$XDG_CONFIG_HOME/llvmenv/config.toml`
+///
+/// ```toml
+/// compiler_launcher = "sccache"
+/// linker = "mold"
+/// ```
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GlobalConfig {
+    /// Default compiler launcher of entries
+    pub compiler_launcher: Option<CompilerLauncher>,
+    /// Default linker of entries
+    pub linker: Option<Linker>,
+}
+
+impl GlobalConfig {
+    /// Load `config.toml`. Default setting is used if not exists.
+    pub fn load() -> Result<Self> {
+        let path = config_dir()?.join(CONFIG_TOML);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(&path).with(&path)?)?)
+    }
+}
+
 /// Initialize configure file
 pub fn init_config() -> Result<()> {
     let dir = config_dir()?;
@@ -43,6 +43,258 @@
 //! Entry is regarded as *local* if there is `path` property, and *remote* if there is `url` property.
 //! Other options are common to *remote* entries.
 //!
+//! Inheritance
+//! ------------
+//! An entry can use the setting of another entry as a base by `inherits` property.
+//! The parent may be an entry defined in the same file or a pre-defined entry shown below:
+//!
+//! ```toml
+//! [my-asan]
+//! inherits = "13.0.0"
+//! target = ["X86"]
+//! option = { LLVM_USE_SANITIZER = "Address" }
+//!
+//! [my-asan-debug]
+//! inherits = "my-asan"
+//! build_type = "Debug"
+//! ```
+//!
+//! The setting of the child is merged into the setting of the parent as follows:
+//!
+//! - `url` or `path` of the child replaces both `url` and `path` of the parent
+//! - `tools` are not inherited by a child with `path`, since they are only used with `url`
+//! - Tables, e.g. `option`, are merged key by key, and the child's value wins
+//! - `tools` are merged by their `name`; a tool of the same name is merged as a table, and others are appended
+//! - Other values, including arrays like `target`, are replaced by the child's
+//!
+//! An entry of the same name as a pre-defined one can inherit it, and customizes the release:
+//!
+//! ```toml
+//! ["13.0.0"]
+//! inherits = "13.0.0"
+//! target = ["X86"]
+//! ```
+//!
+//! Project-local entries
+//! ---------------------
+//! Entries can also be defined in `llvmenv.toml` in the same format as `entry.toml`,
+//! or in `[entries]` table of `.llvmenv` in TOML format:
+//!
+//! ```toml
+//! build = "my-project-llvm"
+//!
+//! [entries.my-project-llvm]
+//! inherits = "13.0.0"
+//! option = { LLVM_ENABLE_ASSERTIONS = "ON" }
+//! ```
+//!
+//! They are searched from the current directory to the root in the same manner as `.llvmenv`,
+//! and shadow the entries of the same name in `entry.toml`.
+//! An entry inheriting its own name refers to the shadowed one.
+//! Relative `path` and `build_dir` are resolved against the directory of the file defining the entry.
+//!
+//! Bootstrap build
+//! ----------------
+//! With `bootstrap` table, llvmenv builds a stage-1 compiler by the host compiler first,
+//! and then builds the entry by the stage-1 clang (and lld if built):
+//!
+//! ```toml
+//! [self-hosted]
+//! inherits = "13.0.0"
+//!
+//! [self-hosted.bootstrap]
+//! name = "13.0.0-stage1"   # shared between entries of the same source
+//! target = ["X86"]
+//! option = { LLVM_INCLUDE_TESTS = "OFF" }
+//! ```
+//!
+//! Only the final stage is installed into the prefix of the entry.
+//! See [BootstrapSetting][BootstrapSetting] for detail.
+//!
+//! [BootstrapSetting]: ./struct.BootstrapSetting.html
+//!
+//! Cross-compilation
+//! ------------------
+//! `host_triple` builds LLVM running on another machine:
+//!
+//! ```toml
+//! [arm-board]
+//! inherits = "13.0.0"
+//! host_triple = "aarch64-linux-gnu"
+//! sysroot = "/opt/sysroots/aarch64"
+//! host_compiler = "build:13.0.0"    # clang can cross-compile by itself
+//! # cmake_toolchain_file = "/path/to/toolchain.cmake"  # instead of generated one
+//! ```
+//!
+//! If the triple differs from the native one, llvmenv generates a CMake toolchain file,
+//! and builds tablegen for the native machine first, without `env` of the entry.
+//! The generated toolchain file only sets the target of clang,
+//! so `cmake_toolchain_file` is required unless the host compiler (or the stage-1 compiler) is clang.
+//! The build is installed as `{name}-{host_triple}`, e.g. `arm-board-aarch64-linux-gnu`.
+//!
+//! PGO and ThinLTO
+//! ----------------
+//! `optimize` property builds an optimized toolchain using the stage-1 compiler:
+//!
+//! ```toml
+//! [fast-clang]
+//! inherits = "13.0.0"
+//! optimize = "pgo+thinlto"  # or "pgo", "thinlto"
+//! pgo_training = "ninja -C ~/my-project/build"  # optional, compiles a bundled C++ file by default
+//! ```
+//!
+//! For PGO, clang instrumented by the stage-1 compiler is built, trained by the workload,
+//! and the profile merged by `llvm-profdata` is used in the final stage.
+//! See [Optimize][Optimize] for detail.
+//!
+//! [Optimize]: ./enum.Optimize.html
+//!
+//! Compiler launcher and linker
+//! -----------------------------
+//! ccache (or sccache) is used if found in PATH, and lld is used if it is built in stage 1 by default.
+//! They can be specified explicitly:
+//!
+//! ```toml
+//! [my-llvm]
+//! inherits = "13.0.0"
+//! compiler_launcher = "sccache"  # "ccache", "none", or "auto"
+//! linker = "mold"                # "lld", "gold", "system", or "auto"
+//! ```
+//!
+//! Defaults for all entries can be set in `$XDG_CONFIG_HOME/llvmenv/config.toml`
+//! with the same keys, and `build-entry` accepts `--compiler-launcher` and `--linker` to overwrite them.
+//!
+//! Parallel jobs
+//! --------------
+//! Compile and link jobs are limited by the available memory, using rough estimates of memory per job
+//! which depend on the build type and LTO. `LLVM_PARALLEL_COMPILE_JOBS` and `LLVM_PARALLEL_LINK_JOBS`
+//! are set for Ninja, and `-j` is reduced for other generators.
+//! Link jobs are also limited to a quarter of the parallel jobs, even if memory is unknown.
+//! `max_memory = "64G"` or `build-entry --max-memory 64G` caps the memory used.
+//!
+//! CMake cache files
+//! ------------------
+//! `cmake_cache` passes cache files to cmake as `-C` options, e.g. to reproduce upstream distribution builds.
+//! Relative paths are resolved against the source directory.
+//! `cmake_args` are passed to cmake as is after all other options:
+//!
+//! ```toml
+//! [release]
+//! inherits = "13.0.0"
+//! cmake_cache = ["clang/cmake/caches/Release.cmake"]
+//! cmake_args = ["-Wno-dev"]
+//! ```
+//!
+//! Distribution components
+//! ------------------------
+//! `install` target installs all libraries and tools. Only required components can be installed:
+//!
+//! ```toml
+//! [dev-clang]
+//! inherits = "13.0.0"
+//! distribution_components = ["clang", "lld", "clang-format", "clangd"]  # by install-distribution
+//! # install_targets = ["clang", "lld"]  # by install-clang and install-lld
+//! strip = true  # use install-*-stripped targets
+//! ```
+//!
+//! Patches
+//! --------
+//! Patches are applied to the source after checkout, and to each tool by its own `patches`:
+//!
+//! ```toml
+//! [old-llvm]
+//! inherits = "8.0.1"
+//! patches = ["patches/glibc-fix.patch", "https://example.com/D12345.diff"]
+//! patch_level = 1  # -p option of patch, 1 by default
+//! ```
+//!
+//! Relative paths are resolved against the directory of the file defining the entry.
+//! Applied patches are recorded with their SHA-256 hashes in `.llvmenv-patches.toml` in the source directory
+//! not to be applied twice, and also in the metadata of the build.
+//! Patches of URL are downloaded only if they are not applied yet, so that a patched source can be built offline.
+//! A patch is checked by `patch --dry-run` first, and the source is never left half-patched.
+//!
+//! Hooks
+//! ------
+//! Shell scripts can be run before/after configure, build, and install of the entry:
+//!
+//! ```toml
+//! [my-llvm.hooks]
+//! post_install = [
+//!   "echo '--gcc-toolchain=/opt/gcc' > $LLVMENV_PREFIX/bin/clang.cfg",
+//!   "rm -f $LLVMENV_PREFIX/lib/*.a",
+//! ]
+//! ```
+//!
+//! See [Hooks][Hooks] for available hooks and environment variables.
+//! Output of the hooks is written to the build log as the other steps.
+//!
+//! [Hooks]: ./struct.Hooks.html
+//!
+//! Environment variables
+//! ----------------------
+//! `env` sets environment variables of all processes in configure, build, and install.
+//! Values are expanded like shell, and `PATH_PREPEND` is prepended to `PATH`:
+//!
+//! ```toml
+//! [legacy]
+//! inherits = "6.0.1"
+//! env = { CXXFLAGS = "-include cstdint", PATH_PREPEND = "~/opt/bin" }
+//! ```
+//!
+//! `llvmenv build-entry --dry-run` prints the commands with these variables without running them.
+//!
+//! Build variants
+//! ---------------
+//! An entry is installed as a build of the same name by default.
+//! `install_name` template or `build-entry --name/--suffix` installs variants of an entry separately:
+//!
+//! ```toml
+//! [my-llvm]
+//! inherits = "13.0.0"
+//! install_name = "{name}-{build_type}"  # {name}, {build_type}, and {triple} are available
+//! ```
+//!
+//! ```shell
+//! llvmenv build-entry my-llvm --build-type Debug   # installed as my-llvm-Debug
+//! llvmenv build-entry 13.0.0 --suffix asan          # installed as 13.0.0-asan
+//! ```
+//!
+//! Each variant has its own build directory `$XDG_CACHE_HOME/llvmenv/builds/{install_name}`.
+//! The root of build directories can be moved by `LLVMENV_BUILD_ROOT` environment variable, e.g. onto a fast disk,
+//! and `build_dir` sets the build directory of the entry explicitly.
+//! `build` directories in the source tree used by old llvmenv are reported by `llvmenv du`, and removed by `llvmenv gc`.
+//!
+//! Pinned revisions
+//! -----------------
+//! A git entry can be pinned to a commit or a tag by `rev` or `tag`, or by the fragment of URL
+//! (`#rev=<sha>` or `#tag=<name>` instead of a branch name):
+//!
+//! ```toml
+//! [llvm-pinned]
+//! url = "https://github.com/llvm/llvm-project"
+//! rev = "d7b669b3a30345cfcdb2fde2af6f48aa4b94845d"
+//! ```
+//!
+//! Only the pinned commit is fetched shallowly, and the source is left in detached HEAD.
+//! `rev` must be the full 40-digit SHA, since abbreviated ones cannot be fetched shallowly.
+//! `build-entry --update` fetches the tip of the branch (or the pinned commit) and checks it out,
+//! which discards the applied patches and applies them again.
+//! The commit SHA of the source is recorded in the metadata of the build.
+//!
+//! Git repositories are shared among entries: each URL is fetched into a bare mirror in `$XDG_CACHE_HOME/llvmenv/git`,
+//! and the source of an entry is its worktree checked out in detached HEAD.
+//! Fetches into a mirror are serialized by a file lock, which is released even if the process is killed,
+//! and concurrent `llvmenv` processes wait for it up to an hour.
+//!
+//! Incremental build
+//! ------------------
+//! A fingerprint of the source revision, the resolved setting, the host compiler, the compiler launcher and linker
+//! (including the defaults in `config.toml`), and the expanded environment variables is stored in the build directory.
+//! `build-entry` does nothing if it is not changed since the last build, and `--force` builds it anyway.
+//! The build directory is cleaned if the generator or build type is changed.
+//! Local sources with uncommitted changes are always built.
+//!
 //! Pre-defined entries
 //! ------------------
 //!
@@ -68,106 +320,230 @@
 use itertools::*;
 use log::{info, warn};
 use semver::{Version, VersionReq};
-use serde_derive::Deserialize;
-use std::{collections::HashMap, fs, path::PathBuf, process, str::FromStr};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    convert::TryFrom,
+    env,
+    ffi::{OsStr, OsString},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+};
+use tempfile::TempDir;
 
-use crate::{config::*, error::*, resource::*};
+use crate::{
+    build::{read_local_env, Build, BuildMetadata, TestSummary, LLVMENV_FN, METADATA_FN},
+    config::*,
+    error::*,
+    resource::*,
+};
 
 /// Option for CMake Generators
 ///
 /// - Official document: [CMake Generators](https://cmake.org/cmake/help/latest/manual/cmake-generators.7.html)
 ///
+/// Any generator name of cmake is accepted, and it is validated by `cmake --help` before configure.
+///
 /// ```
 /// use llvmenv::entry::CMakeGenerator;
 /// use std::str::FromStr;
 /// assert_eq!(CMakeGenerator::from_str("Makefile").unwrap(), CMakeGenerator::Makefile);
+/// assert_eq!(CMakeGenerator::from_str("Unix Makefiles").unwrap(), CMakeGenerator::Makefile);
 /// assert_eq!(CMakeGenerator::from_str("Ninja").unwrap(), CMakeGenerator::Ninja);
+/// assert_eq!(CMakeGenerator::from_str("Ninja Multi-Config").unwrap(), CMakeGenerator::NinjaMultiConfig);
 /// assert_eq!(CMakeGenerator::from_str("vs").unwrap(), CMakeGenerator::VisualStudio);
 /// assert_eq!(CMakeGenerator::from_str("VisualStudio").unwrap(), CMakeGenerator::VisualStudio);
-/// assert!(CMakeGenerator::from_str("MySuperBuilder").is_err());
+/// assert_eq!(CMakeGenerator::from_str("VisualStudioWin64").unwrap(), CMakeGenerator::VisualStudioWin64);
+/// assert_eq!(
+///     CMakeGenerator::from_str("Visual Studio 17 2022").unwrap(),
+///     CMakeGenerator::Other("Visual Studio 17 2022".into())
+/// );
+/// assert!(CMakeGenerator::from_str("").is_err());
 /// ```
-#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+#[serde(try_from = "String", into = "String")]
 pub enum CMakeGenerator {
     /// Use platform default generator (without -G option)
+    #[default]
     Platform,
     /// Unix Makefile
     Makefile,
     /// Ninja generator
     Ninja,
+    /// Ninja Multi-Config generator
+    NinjaMultiConfig,
     /// Visual Studio 15 2017
     VisualStudio,
     /// Visual Studio 15 2017 Win64
     VisualStudioWin64,
-}
-
-impl Default for CMakeGenerator {
-    fn default() -> Self {
-        CMakeGenerator::Platform
-    }
+    /// Other generator by its name in cmake, e.g. "Visual Studio 17 2022" or "Xcode"
+    Other(String),
 }
 
 impl FromStr for CMakeGenerator {
     type Err = Error;
     fn from_str(generator: &str) -> Result<Self> {
         Ok(match generator.to_ascii_lowercase().as_str() {
-            "makefile" => CMakeGenerator::Makefile,
+            "platform" => CMakeGenerator::Platform,
+            "makefile" | "unix makefiles" => CMakeGenerator::Makefile,
             "ninja" => CMakeGenerator::Ninja,
+            "ninja multi-config" | "ninjamulticonfig" => CMakeGenerator::NinjaMultiConfig,
             "visualstudio" | "vs" => CMakeGenerator::VisualStudio,
-            _ => {
+            "visualstudiowin64" => CMakeGenerator::VisualStudioWin64,
+            "" => {
                 return Err(Error::UnsupportedGenerator {
                     generator: generator.into(),
                 });
             }
+            _ => CMakeGenerator::Other(generator.into()),
         })
     }
 }
 
+impl TryFrom<String> for CMakeGenerator {
+    type Error = Error;
+    fn try_from(generator: String) -> Result<Self> {
+        Self::from_str(&generator)
+    }
+}
+
+impl From<CMakeGenerator> for String {
+    fn from(generator: CMakeGenerator) -> Self {
+        match generator {
+            CMakeGenerator::Platform => "Platform".into(),
+            CMakeGenerator::VisualStudio => "VisualStudio".into(),
+            CMakeGenerator::VisualStudioWin64 => "VisualStudioWin64".into(),
+            generator => generator.name().unwrap_or_default().into(),
+        }
+    }
+}
+
 impl CMakeGenerator {
+    /// Name of generator in cmake, `None` for the platform default
+    pub fn name(&self) -> Option<&str> {
+        Some(match self {
+            CMakeGenerator::Platform => return None,
+            CMakeGenerator::Makefile => "Unix Makefiles",
+            CMakeGenerator::Ninja => "Ninja",
+            CMakeGenerator::NinjaMultiConfig => "Ninja Multi-Config",
+            CMakeGenerator::VisualStudio => "Visual Studio 15 2017",
+            CMakeGenerator::VisualStudioWin64 => "Visual Studio 15 2017 Win64",
+            CMakeGenerator::Other(name) => name,
+        })
+    }
+
     /// Option for cmake
     pub fn option(&self) -> Vec<String> {
-        match self {
-            CMakeGenerator::Platform => Vec::new(),
-            CMakeGenerator::Makefile => vec!["-G", "Unix Makefiles"],
-            CMakeGenerator::Ninja => vec!["-G", "Ninja"],
-            CMakeGenerator::VisualStudio => vec!["-G", "Visual Studio 15 2017"],
-            CMakeGenerator::VisualStudioWin64 => {
-                vec!["-G", "Visual Studio 15 2017 Win64", "-Thost=x64"]
+        let mut opts = Vec::new();
+        if let Some(name) = self.name() {
+            opts.push("-G".into());
+            opts.push(name.into());
+        }
+        if *self == CMakeGenerator::VisualStudioWin64 {
+            opts.push("-Thost=x64".into());
+        }
+        opts
+    }
+
+    /// Generator uses Ninja, which supports job pools
+    pub fn is_ninja(&self) -> bool {
+        self.name().is_some_and(|name| name.contains("Ninja"))
+    }
+
+    /// Generator supports multiple configurations, and requires `--config` to build
+    pub fn is_multi_config(&self) -> bool {
+        self.name().is_some_and(|name| {
+            name.starts_with("Visual Studio") || name == "Xcode" || name == "Ninja Multi-Config"
+        })
+    }
+
+    /// Directory of executables in the build directory of LLVM,
+    /// which is in the directory of the configuration for multi-config generators, e.g. `Release/bin`
+    pub fn bin_dir(&self, build_dir: &Path, build_type: BuildType) -> PathBuf {
+        if self.is_multi_config() {
+            build_dir.join(format!("{:?}", build_type)).join("bin")
+        } else {
+            build_dir.join("bin")
+        }
+    }
+
+    /// Check the generator is available in cmake, optionally with an extra generator
+    /// such as "CodeBlocks"
+    pub fn validate(&self, extra: Option<&str>) -> Result<()> {
+        let name = match (self.name(), extra) {
+            // Extra generator without generator is rejected by the entry
+            (None, _) => return Ok(()),
+            (Some(name), None) => name.to_string(),
+            (Some(name), Some(extra)) => format!("{} - {}", extra, name),
+        };
+        let (help, _) = process::Command::new("cmake")
+            .arg("--help")
+            .check_output()?;
+        let available = parse_generators(&help);
+        let found = available.iter().any(|generator| {
+            // e.g. "Visual Studio 15 2017 [arch]" accepts "Visual Studio 15 2017 Win64"
+            match generator.strip_suffix(" [arch]") {
+                Some(base) => name.starts_with(base),
+                None => *generator == name,
             }
+        });
+        if found {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedGenerator { generator: name })
         }
-        .into_iter()
-        .map(|s| s.into())
-        .collect()
     }
 
     /// Option for cmake build mode (`cmake --build` command)
     pub fn build_option(&self, nproc: usize, build_type: BuildType) -> Vec<String> {
         match self {
-            CMakeGenerator::VisualStudioWin64 | CMakeGenerator::VisualStudio => {
-                vec!["--config".into(), format!("{:?}", build_type)]
-            }
-            CMakeGenerator::Platform => Vec::new(),
             CMakeGenerator::Makefile | CMakeGenerator::Ninja => {
                 vec!["--".into(), "-j".into(), format!("{}", nproc)]
             }
+            _ if self.is_multi_config() => vec![
+                "--config".into(),
+                format!("{:?}", build_type),
+                "--parallel".into(),
+                format!("{}", nproc),
+            ],
+            _ => vec!["--parallel".into(), format!("{}", nproc)],
         }
     }
 }
 
+/// Names of generators in the output of `cmake --help`
+fn parse_generators(help: &str) -> Vec<String> {
+    help.lines()
+        .skip_while(|line| line.trim() != "Generators")
+        .filter_map(|line| {
+            // Generator lines start with "  " or "* " (default), and descriptions are indented more
+            let name = line
+                .strip_prefix("  ")
+                .or_else(|| line.strip_prefix("* "))?;
+            if name.starts_with(' ') || name.starts_with('=') {
+                return None;
+            }
+            Some(name.split(" =").next()?.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
 /// CMake build type
-#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
 pub enum BuildType {
     Debug,
+    #[default]
     Release,
     RelWithDebInfo,
     MinSizeRel,
 }
 
-impl Default for BuildType {
-    fn default() -> Self {
-        BuildType::Release
-    }
-}
-
 impl FromStr for BuildType {
     type Err = Error;
 
@@ -184,8 +560,249 @@ impl FromStr for BuildType {
     }
 }
 
+/// Optimization of the toolchain itself
+///
+/// - `pgo`: Profile guided optimization. Clang instrumented by the stage-1 compiler is trained
+///   by a workload, and the final stage is built using the profile.
+/// - `thinlto`: Build the final stage with ThinLTO by the stage-1 compiler
+/// - `pgo+thinlto`: Both of them
+///
+/// Stage-1 compiler is built with the default [BootstrapSetting] if `bootstrap` is not specified.
+/// It must contain compiler-rt for PGO, and lld for ThinLTO, which are added to `LLVM_ENABLE_PROJECTS` of monorepo.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Optimize {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "pgo")]
+    Pgo,
+    #[serde(rename = "thinlto")]
+    ThinLto,
+    #[serde(rename = "pgo+thinlto")]
+    PgoThinLto,
+}
+
+impl Optimize {
+    pub fn pgo(self) -> bool {
+        matches!(self, Optimize::Pgo | Optimize::PgoThinLto)
+    }
+
+    pub fn thinlto(self) -> bool {
+        matches!(self, Optimize::ThinLto | Optimize::PgoThinLto)
+    }
+}
+
+/// Compiler used to build LLVM
+///
+/// ```
+/// use llvmenv::entry::HostCompiler;
+/// use std::{path::PathBuf, str::FromStr};
+/// assert_eq!(HostCompiler::from_str("system").unwrap(), HostCompiler::System);
+/// assert_eq!(HostCompiler::from_str("build:12.0.1").unwrap(), HostCompiler::Build("12.0.1".into()));
+/// assert_eq!(HostCompiler::from_str("/usr/bin/gcc-7").unwrap(), HostCompiler::Path(PathBuf::from("/usr/bin/gcc-7")));
+/// ```
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum HostCompiler {
+    /// Compiler detected by cmake
+    #[default]
+    System,
+    /// Clang in a build managed by llvmenv, e.g. `build:12.0.1`
+    Build(String),
+    /// Path of C compiler, e.g. `/usr/bin/gcc-7`. C++ compiler is searched in the same directory.
+    Path(PathBuf),
+}
+
+impl FromStr for HostCompiler {
+    type Err = Error;
+    fn from_str(compiler: &str) -> Result<Self> {
+        Ok(if compiler == "system" {
+            HostCompiler::System
+        } else if let Some(name) = compiler.strip_prefix("build:") {
+            HostCompiler::Build(name.into())
+        } else {
+            HostCompiler::Path(PathBuf::from(shellexpand::tilde(compiler).to_string()))
+        })
+    }
+}
+
+impl TryFrom<String> for HostCompiler {
+    type Error = Error;
+    fn try_from(compiler: String) -> Result<Self> {
+        Self::from_str(&compiler)
+    }
+}
+
+impl From<HostCompiler> for String {
+    fn from(compiler: HostCompiler) -> Self {
+        match compiler {
+            HostCompiler::System => "system".into(),
+            HostCompiler::Build(name) => format!("build:{}", name),
+            HostCompiler::Path(path) => path.display().to_string(),
+        }
+    }
+}
+
+/// `bin` directory of a build managed by llvmenv
+fn build_bin(name: &str) -> Result<PathBuf> {
+    let build = Build::from_name(name)?;
+    if !build.exists() {
+        return Err(Error::BuildNotFound { name: name.into() });
+    }
+    Ok(build.prefix().join("bin"))
+}
+
+impl HostCompiler {
+    /// Path of clang if this compiler is clang
+    fn clang(&self) -> Result<Option<PathBuf>> {
+        Ok(match self {
+            HostCompiler::System => None,
+            HostCompiler::Build(name) => Some(build_bin(name)?.join("clang")),
+            HostCompiler::Path(cc) => Some(cc.clone()).filter(|cc| is_clang(cc)),
+        })
+    }
+
+    /// Options for cmake to use this compiler
+    pub fn option(&self) -> Result<Vec<String>> {
+        Ok(match self {
+            HostCompiler::System => Vec::new(),
+            HostCompiler::Build(name) => {
+                let bin = build_bin(name)?;
+                toolchain_options(&bin.join("clang"), &bin.join("clang++"))
+            }
+            HostCompiler::Path(cc) => {
+                let name = cc.file_name().and_then(|name| name.to_str()).unwrap_or("");
+                // e.g. clang-12 -> clang++-12, aarch64-linux-gnu-gcc -> aarch64-linux-gnu-g++
+                let cxx = if let Some(i) = name.rfind("clang") {
+                    format!("{}clang++{}", &name[..i], &name[i + 5..])
+                } else if let Some(i) = name.rfind("gcc") {
+                    format!("{}g++{}", &name[..i], &name[i + 3..])
+                } else if name == "cc" || name.ends_with("-cc") {
+                    format!("{}c++", &name[..name.len() - 2])
+                } else {
+                    return Err(Error::UnknownCompiler { path: cc.clone() });
+                };
+                toolchain_options(cc, &cc.with_file_name(cxx))
+            }
+        })
+    }
+}
+
+/// Compiler launcher to cache the compilation
+///
+/// `auto` uses ccache or sccache if found in PATH (ccache is preferred).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CompilerLauncher {
+    #[default]
+    Auto,
+    Ccache,
+    Sccache,
+    None,
+}
+
+impl FromStr for CompilerLauncher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(CompilerLauncher::Auto),
+            "ccache" => Ok(CompilerLauncher::Ccache),
+            "sccache" => Ok(CompilerLauncher::Sccache),
+            "none" => Ok(CompilerLauncher::None),
+            _ => Err(Error::UnsupportedCompilerLauncher {
+                launcher: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl CompilerLauncher {
+    /// Resolve `auto` into the launcher found in PATH
+    pub fn resolve(self) -> Self {
+        match self {
+            CompilerLauncher::Auto => {
+                if which::which("ccache").is_ok() {
+                    CompilerLauncher::Ccache
+                } else if which::which("sccache").is_ok() {
+                    CompilerLauncher::Sccache
+                } else {
+                    CompilerLauncher::None
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Options for cmake. `auto` must be resolved before.
+    pub fn option(self) -> Vec<String> {
+        match self {
+            CompilerLauncher::Ccache => vec!["-DLLVM_CCACHE_BUILD=ON".into()],
+            CompilerLauncher::Sccache => vec![
+                "-DCMAKE_C_COMPILER_LAUNCHER=sccache".into(),
+                "-DCMAKE_CXX_COMPILER_LAUNCHER=sccache".into(),
+            ],
+            CompilerLauncher::Auto | CompilerLauncher::None => Vec::new(),
+        }
+    }
+}
+
+/// Linker used to build LLVM
+///
+/// `auto` uses lld if it is built with the stage-1 compiler, and the system linker otherwise.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Linker {
+    #[default]
+    Auto,
+    Lld,
+    Gold,
+    Mold,
+    System,
+}
+
+impl FromStr for Linker {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Linker::Auto),
+            "lld" => Ok(Linker::Lld),
+            "gold" => Ok(Linker::Gold),
+            "mold" => Ok(Linker::Mold),
+            "system" => Ok(Linker::System),
+            _ => Err(Error::UnsupportedLinker {
+                linker: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl Linker {
+    /// Resolve `auto` into lld if it is installed in the prefix of the stage-1 compiler
+    pub fn resolve(self, stage1: Option<&Path>) -> Self {
+        match (self, stage1) {
+            (Linker::Auto, Some(stage1)) if stage1.join("bin").join("ld.lld").exists() => {
+                Linker::Lld
+            }
+            (Linker::Auto, _) => Linker::System,
+            _ => self,
+        }
+    }
+
+    /// Options for cmake. `auto` must be resolved before.
+    pub fn option(self) -> Vec<String> {
+        match self {
+            Linker::Lld => vec!["-DLLVM_ENABLE_LLD=ON".into()],
+            Linker::Gold => vec!["-DLLVM_USE_LINKER=gold".into()],
+            Linker::Mold => vec!["-DLLVM_USE_LINKER=mold".into()],
+            Linker::Auto | Linker::System => Vec::new(),
+        }
+    }
+}
+
 /// LLVM Tools e.g. clang, compiler-rt, and so on.
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Tool {
     /// Name of tool (will be downloaded into `tools/{name}` by default)
     pub name: String,
@@ -198,6 +815,13 @@ pub struct Tool {
 
     /// Relative install Path (see the example of clang-extra in [module level doc](index.html))
     pub relative_path: Option<String>,
+
+    /// Patches applied to the tool, see `patches` of [EntrySetting]
+    #[serde(default)]
+    pub patches: Vec<String>,
+
+    /// Patch level (`-p` option of patch) for the patches of the tool. Same as the entry's by default
+    pub patch_level: Option<usize>,
 }
 
 impl Tool {
@@ -207,11 +831,13 @@ impl Tool {
             url: url.into(),
             branch: None,
             relative_path: None,
+            patches: Vec::new(),
+            patch_level: None,
         }
     }
 
-    fn rel_path(&self) -> String {
-        match self.relative_path {
+    fn rel_path(&self) -> Result<String> {
+        Ok(match self.relative_path {
             Some(ref rel_path) => rel_path.to_string(),
             None => match self.name.as_str() {
                 "clang" | "lld" | "lldb" | "polly" => format!("tools/{}", self.name),
@@ -219,23 +845,34 @@ impl Tool {
                 "compiler-rt" | "libcxx" | "libcxxabi" | "libunwind" | "openmp" => {
                     format!("projects/{}", self.name)
                 }
-                _ => panic!(
-                    "Unknown tool. Please specify its relative path explicitly: {}",
-                    self.name
-                ),
+                _ => {
+                    return Err(Error::UnknownTool {
+                        name: self.name.clone(),
+                    })
+                }
             },
-        }
+        })
     }
 }
 
 /// Setting for both Remote and Local entries. TOML setting file will be decoded into this struct.
 ///
 ///
-#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 pub struct EntrySetting {
+    /// Name of another entry (user-defined or official) whose setting is used as a base,
+    /// see [module level document](index.html) for merge rules
+    pub inherits: Option<String>,
+
     /// URL of remote LLVM resource, see also [resouce](../resource/index.html) module
     pub url: Option<String>,
 
+    /// Full commit SHA (40 hex digits) to pin the git repository of `url`. Only the commit is fetched shallowly
+    pub rev: Option<String>,
+
+    /// Tag to pin the git repository of `url`, e.g. `"llvmorg-13.0.0"`
+    pub tag: Option<String>,
+
     /// Path of local LLVM source dir
     pub path: Option<String>,
 
@@ -251,6 +888,18 @@ pub struct EntrySetting {
     #[serde(default)]
     pub generator: CMakeGenerator,
 
+    /// Platform of generator (-A option in cmake), e.g. `"x64"` for Visual Studio.
+    /// Not allowed with generators having the architecture in their names, e.g. `VisualStudioWin64`
+    pub generator_platform: Option<String>,
+
+    /// Toolset of generator (-T option in cmake), e.g. `"host=x64"` or `"ClangCL"`.
+    /// Appended to `host=x64` of `VisualStudioWin64`
+    pub generator_toolset: Option<String>,
+
+    /// Extra generator for IDE project files, e.g. `"CodeBlocks"` or `"Sublime Text 2"`.
+    /// `generator` must be set explicitly
+    pub extra_generator: Option<String>,
+
     ///  Option for `CMAKE_BUILD_TYPE`
     #[serde(default)]
     pub build_type: BuildType,
@@ -258,42 +907,352 @@ pub struct EntrySetting {
     /// Additional LLVM build options
     #[serde(default)]
     pub option: HashMap<String, String>,
-}
 
-/// Describes how to compile LLVM/Clang
-///
-/// See also [module level document](index.html).
-#[derive(Debug, PartialEq)]
-pub enum Entry {
-    Remote {
-        name: String,
-        version: Option<Version>,
-        url: String,
-        tools: Vec<Tool>,
-        setting: EntrySetting,
-    },
-    Local {
-        name: String,
-        version: Option<Version>,
-        path: PathBuf,
-        setting: EntrySetting,
-    },
-}
+    /// CMake cache files to populate the cache, e.g. `["clang/cmake/caches/Release.cmake"]`.
+    /// Relative path is resolved against the source directory.
+    #[serde(default)]
+    pub cmake_cache: Vec<String>,
 
-fn load_entry_toml(toml_str: &str) -> Result<Vec<Entry>> {
-    let entries: HashMap<String, EntrySetting> = toml::from_str(toml_str)?;
-    entries
-        .into_iter()
-        .map(|(name, setting)| Entry::parse_setting(&name, Version::parse(&name).ok(), setting))
-        .collect()
-}
+    /// Additional cmake arguments passed as is
+    #[serde(default)]
+    pub cmake_args: Vec<String>,
 
-pub fn official_releases() -> Vec<Entry> {
-    vec![
-        Entry::official(13, 0, 0),
-        Entry::official(12, 0, 1),
-        Entry::official(12, 0, 0),
-        Entry::official(11, 1, 0),
+    /// Components installed by `install-distribution` target instead of `install`,
+    /// e.g. `["clang", "lld", "clang-format", "clangd"]`. It sets `LLVM_DISTRIBUTION_COMPONENTS`.
+    #[serde(default)]
+    pub distribution_components: Vec<String>,
+
+    /// Components installed by `install-{component}` targets instead of `install`
+    #[serde(default)]
+    pub install_targets: Vec<String>,
+
+    /// Strip installed binaries
+    #[serde(default)]
+    pub strip: bool,
+
+    /// Shell scripts run before/after each step of the build
+    #[serde(default)]
+    pub hooks: Hooks,
+
+    /// Environment variables for the processes in the build, e.g. `CXXFLAGS`.
+    /// Values are expanded in shell-like way, and `PATH_PREPEND` is prepended to `PATH`.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+
+    /// Patches applied to the source after checkout, paths or URLs.
+    /// Relative path is resolved against the directory of the file defining the entry.
+    #[serde(default)]
+    pub patches: Vec<String>,
+
+    /// Patch level (`-p` option of patch), 1 by default
+    pub patch_level: Option<usize>,
+
+    /// Build stage-1 compiler first, and build this entry by it (two-stage bootstrap build)
+    pub bootstrap: Option<BootstrapSetting>,
+
+    /// Compiler to build LLVM, e.g. `"system"`, `"build:12.0.1"`, or `"/usr/bin/gcc-7"`.
+    /// It builds stage-1 compiler in bootstrap build.
+    #[serde(default)]
+    pub host_compiler: HostCompiler,
+
+    /// Triple of the host where the build runs, e.g. `aarch64-linux-gnu`.
+    /// The build is cross-compiled if it differs from the native one, and installed into `{name}-{host_triple}`.
+    pub host_triple: Option<String>,
+
+    /// Build directory. Relative path is resolved against the directory of the file defining the entry.
+    /// `$LLVMENV_BUILD_ROOT/{install_name}` is used by default.
+    pub build_dir: Option<String>,
+
+    /// Template of the build name installed by this entry, e.g. `"{name}-{build_type}"`.
+    /// `{name}`, `{build_type}`, and `{triple}` (host triple) are available.
+    /// `"{name}"` is used by default, or `"{name}-{triple}"` if `host_triple` is set.
+    pub install_name: Option<String>,
+
+    /// Sysroot of the host for cross-compilation
+    pub sysroot: Option<String>,
+
+    /// CMake toolchain file used instead of the generated one for cross-compilation.
+    /// Relative path is resolved against the source directory.
+    pub cmake_toolchain_file: Option<String>,
+
+    /// Optimize the toolchain by PGO and/or ThinLTO
+    #[serde(default)]
+    pub optimize: Optimize,
+
+    /// Shell command used as the training workload of PGO instead of the bundled one.
+    /// It runs in a temporary directory with `CC` and `CXX` of the instrumented clang.
+    pub pgo_training: Option<String>,
+
+    /// Compiler launcher, `"ccache"`, `"sccache"`, `"none"`, or `"auto"`.
+    /// The default in `config.toml` is used if not specified.
+    pub compiler_launcher: Option<CompilerLauncher>,
+
+    /// Linker, `"lld"`, `"gold"`, `"mold"`, `"system"`, or `"auto"`.
+    /// The default in `config.toml` is used if not specified.
+    pub linker: Option<Linker>,
+
+    /// Upper limit of memory used to estimate parallel jobs, e.g. `"64G"`.
+    /// Available memory of the machine is used if not specified.
+    pub max_memory: Option<String>,
+}
+
+/// Shell scripts run in the build of an entry
+///
+/// Each script runs by `sh -c` in the build directory with the following environment variables:
+///
+/// - `LLVMENV_ENTRY`: Name of the entry
+/// - `LLVMENV_SRC_DIR`: Source directory
+/// - `LLVMENV_BUILD_DIR`: Build directory
+/// - `LLVMENV_PREFIX`: Install prefix
+///
+/// Hooks run only for the final stage, not for the stage-1 compiler of bootstrap build.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Hooks {
+    #[serde(default)]
+    pub pre_configure: Vec<String>,
+    #[serde(default)]
+    pub post_configure: Vec<String>,
+    #[serde(default)]
+    pub pre_build: Vec<String>,
+    #[serde(default)]
+    pub post_build: Vec<String>,
+    #[serde(default)]
+    pub pre_install: Vec<String>,
+    #[serde(default)]
+    pub post_install: Vec<String>,
+}
+
+/// Setting for the stage-1 compiler of two-stage bootstrap build
+///
+/// Stage-1 compiler is built from the same source by the host compiler,
+/// and installed into `$XDG_CACHE_HOME/llvmenv/stage1/{name}/{key}`, where the key is a hash of the source revisions.
+/// Stage-1 compilers of different sources are installed separately even if they have the same name.
+/// The source must contain clang, and lld is used as a linker if it is also built.
+/// For monorepo, i.e. if `LLVM_ENABLE_PROJECTS` is set, clang is added to the projects of stage 1.
+/// It is rebuilt if the setting of stage 1 or the host compiler is changed.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub struct BootstrapSetting {
+    /// Name of stage-1 compiler. Entries using the same name share the stage-1 compiler,
+    /// and it is built only once. The entry name with `-stage1` suffix is used by default.
+    pub name: Option<String>,
+
+    /// Target to be build in stage 1. Same as the entry's if empty
+    #[serde(default)]
+    pub target: Vec<String>,
+
+    ///  Option for `CMAKE_BUILD_TYPE` in stage 1
+    #[serde(default)]
+    pub build_type: BuildType,
+
+    /// Additional LLVM build options for stage 1. Options of the entry are not used in stage 1.
+    #[serde(default)]
+    pub option: HashMap<String, String>,
+}
+
+/// Patch applied to the source, recorded in `.llvmenv-patches.toml` in the source directory
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AppliedPatch {
+    /// Path or URL of the patch
+    pub patch: String,
+    /// Directory where the patch is applied, relative to the source directory
+    pub dir: String,
+    /// SHA-256 hash of the patch
+    pub sha256: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PatchStamp {
+    #[serde(default)]
+    patches: Vec<AppliedPatch>,
+}
+
+const PATCH_STAMP_FN: &str = ".llvmenv-patches.toml";
+
+/// Apply a patch in `dir`. It is checked by `--dry-run` first not to leave the source half-patched.
+fn apply_patch(patch: &str, content: &[u8], dir: &Path, level: usize) -> Result<()> {
+    let mut file = tempfile::NamedTempFile::new().with(env::temp_dir())?;
+    file.write_all(content).with(file.path())?;
+    for dry_run in &[true, false] {
+        let mut cmd = process::Command::new("patch");
+        cmd.arg(format!("-p{}", level))
+            .args(["--forward", "--batch", "-i"])
+            .arg(file.path())
+            .current_dir(dir);
+        if *dry_run {
+            cmd.arg("--dry-run");
+        }
+        cmd.check_output().map_err(|e| match e {
+            Error::CommandError { stdout, stderr, .. } => Error::PatchFailed {
+                patch: patch.into(),
+                dir: dir.into(),
+                output: format!(
+                    "{}{}",
+                    stdout.unwrap_or_default(),
+                    stderr.unwrap_or_default()
+                ),
+            },
+            e => e,
+        })?;
+    }
+    Ok(())
+}
+
+/// Describes how to compile LLVM/Clang
+///
+/// See also [module level document](index.html).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Remote {
+        name: String,
+        version: Option<Version>,
+        url: String,
+        tools: Vec<Tool>,
+        setting: EntrySetting,
+    },
+    Local {
+        name: String,
+        version: Option<Version>,
+        path: PathBuf,
+        setting: EntrySetting,
+    },
+}
+
+/// Load entries from tables of settings. The former table shadows the latter one.
+fn load_entry_tables(layers: &[toml::value::Table]) -> Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for (i, table) in layers.iter().enumerate() {
+        for name in table.keys() {
+            if entries.iter().any(|entry| entry.name() == name) {
+                continue;
+            }
+            let setting = resolve_setting(&layers[i..], name, &mut Vec::new())?.try_into()?;
+            entries.push(Entry::parse_setting(
+                name,
+                Version::parse(name).ok(),
+                setting,
+            )?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Resolve `inherits` of the entry in `layers[0]` recursively, and returns the merged setting
+///
+/// The parent is searched from the same layer to the lower layers, and then official releases.
+/// An entry inheriting its own name refers the entry shadowed by itself.
+fn resolve_setting(
+    layers: &[toml::value::Table],
+    name: &str,
+    visiting: &mut Vec<(usize, String)>,
+) -> Result<toml::Value> {
+    let invalid = |message: String| Error::InvalidEntry {
+        name: name.into(),
+        message,
+    };
+    let id = (layers.len(), name.to_string());
+    if visiting.contains(&id) {
+        return Err(invalid(format!(
+            "Cyclic inheritance: {} -> {}",
+            visiting.iter().map(|(_, name)| name).join(" -> "),
+            name
+        )));
+    }
+    let setting = layers[0][name].clone();
+    let parent = match setting.get("inherits") {
+        Some(toml::Value::String(parent)) => parent.clone(),
+        Some(_) => return Err(invalid("'inherits' must be a string".into())),
+        None => return Ok(setting),
+    };
+    let lower = if parent == name { &layers[1..] } else { layers };
+    let base = if let Some(i) = lower.iter().position(|t| t.contains_key(&parent)) {
+        visiting.push(id);
+        let base = resolve_setting(&lower[i..], &parent, visiting)?;
+        visiting.pop();
+        base
+    } else if let Some(official) = official_releases()?
+        .into_iter()
+        .find(|entry| entry.name() == parent)
+    {
+        toml::Value::try_from(official.setting()).map_err(|e| invalid(e.to_string()))?
+    } else {
+        return Err(invalid(format!("Parent entry '{}' not found", parent)));
+    };
+    Ok(merge_setting(base, setting))
+}
+
+/// Merge the setting of child entry (or tool) into the setting of its parent
+fn merge_setting(base: toml::Value, child: toml::Value) -> toml::Value {
+    let (mut base, child) = match (base, child) {
+        (toml::Value::Table(base), toml::Value::Table(child)) => (base, child),
+        (_, child) => return child,
+    };
+    // Source of the child replaces the source of the parent
+    if child.contains_key("url") || child.contains_key("path") {
+        base.remove("url");
+        base.remove("path");
+        base.remove("rev");
+        base.remove("tag");
+    }
+    // Tools are only used with URL
+    if child.contains_key("path") {
+        base.remove("tools");
+    }
+    if child.contains_key("rev") || child.contains_key("tag") {
+        base.remove("rev");
+        base.remove("tag");
+    }
+    for (key, value) in child {
+        let merged = match (key.as_str(), base.remove(&key)) {
+            ("tools", Some(toml::Value::Array(tools))) => merge_tools(tools, value),
+            (_, Some(base)) => merge_value(base, value),
+            (_, None) => value,
+        };
+        base.insert(key, merged);
+    }
+    toml::Value::Table(base)
+}
+
+/// Tables are merged recursively, and other values are replaced by the child's
+fn merge_value(base: toml::Value, child: toml::Value) -> toml::Value {
+    match (base, child) {
+        (toml::Value::Table(mut base), toml::Value::Table(child)) => {
+            for (key, value) in child {
+                let merged = match base.remove(&key) {
+                    Some(base) => merge_value(base, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            toml::Value::Table(base)
+        }
+        (_, child) => child,
+    }
+}
+
+/// Tools are merged by their name
+fn merge_tools(mut tools: Vec<toml::Value>, child: toml::Value) -> toml::Value {
+    let child = match child {
+        toml::Value::Array(child) => child,
+        child => return child,
+    };
+    for tool in child {
+        match tools
+            .iter_mut()
+            .find(|base| base.get("name").is_some() && base.get("name") == tool.get("name"))
+        {
+            Some(base) => *base = merge_setting(base.clone(), tool),
+            None => tools.push(tool),
+        }
+    }
+    toml::Value::Array(tools)
+}
+
+pub fn official_releases() -> Result<Vec<Entry>> {
+    vec![
+        Entry::official(13, 0, 0),
+        Entry::official(12, 0, 1),
+        Entry::official(12, 0, 0),
+        Entry::official(11, 1, 0),
         Entry::official(11, 0, 0),
         Entry::official(10, 0, 1),
         Entry::official(10, 0, 0),
@@ -313,13 +1272,99 @@ pub fn official_releases() -> Vec<Entry> {
         Entry::official(3, 9, 1),
         Entry::official(3, 9, 0),
     ]
+    .into_iter()
+    .collect()
+}
+
+/// Resolve relative paths in the settings of entries against `dir`, where the file defining them exists
+fn resolve_relative_paths(entries: &mut toml::value::Table, dir: &Path) {
+    for (_, setting) in entries.iter_mut() {
+        let setting = match setting.as_table_mut() {
+            Some(setting) => setting,
+            None => continue,
+        };
+        for key in &["path", "build_dir"] {
+            if let Some(toml::Value::String(path)) = setting.get_mut(*key) {
+                *path = resolve_relative_path(path, dir);
+            }
+        }
+        resolve_patch_paths(setting, dir);
+        if let Some(toml::Value::Array(tools)) = setting.get_mut("tools") {
+            for tool in tools.iter_mut().filter_map(|tool| tool.as_table_mut()) {
+                resolve_patch_paths(tool, dir);
+            }
+        }
+    }
+}
+
+/// Resolve relative paths in `patches` of the entry or tool. URLs are kept as is.
+fn resolve_patch_paths(setting: &mut toml::value::Table, dir: &Path) {
+    if let Some(toml::Value::Array(patches)) = setting.get_mut("patches") {
+        for patch in patches.iter_mut() {
+            if let toml::Value::String(patch) = patch {
+                if !is_url(patch) {
+                    *patch = resolve_relative_path(patch, dir);
+                }
+            }
+        }
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Join a relative path to `dir`. Paths starting with `~` or environment variables are kept as is.
+fn resolve_relative_path(path: &str, dir: &Path) -> String {
+    match shellexpand::full(path) {
+        Ok(expanded) if Path::new(expanded.as_ref()).is_relative() => {
+            dir.join(path).display().to_string()
+        }
+        _ => path.into(),
+    }
+}
+
+/// Seek project-local entries from `$PWD` to the root in the same manner as `.llvmenv`.
+/// They are defined in `llvmenv.toml` in the same format as `entry.toml`,
+/// or in `[entries]` table of `.llvmenv`.
+fn seek_project_entries() -> Result<Option<(PathBuf, toml::value::Table)>> {
+    for dir in env::current_dir()?.ancestors() {
+        let project_toml = dir.join(PROJECT_TOML);
+        if project_toml.exists() {
+            let entries = toml::from_str(&fs::read_to_string(&project_toml).with(&project_toml)?)?;
+            return Ok(Some((project_toml, entries)));
+        }
+        if let Some(local) = read_local_env(dir)? {
+            if !local.entries.is_empty() {
+                return Ok(Some((dir.join(LLVMENV_FN), local.entries)));
+            }
+        }
+    }
+    Ok(None)
 }
 
 pub fn load_entries() -> Result<Vec<Entry>> {
+    let mut layers = Vec::new();
+    if let Some((path, mut entries)) = seek_project_entries()? {
+        info!("Use project-local entries in {}", path.display());
+        resolve_relative_paths(&mut entries, path.parent().unwrap());
+        layers.push(entries);
+    }
     let global_toml = config_dir()?.join(ENTRY_TOML);
-    let mut entries = load_entry_toml(&fs::read_to_string(&global_toml).with(&global_toml)?)?;
-    let mut official = official_releases();
-    entries.append(&mut official);
+    if global_toml.exists() {
+        let mut entries = toml::from_str(&fs::read_to_string(&global_toml).with(&global_toml)?)?;
+        resolve_relative_paths(&mut entries, &config_dir()?);
+        layers.push(entries);
+    } else if layers.is_empty() {
+        return Err(Error::ConfigureNotFound { path: global_toml });
+    }
+    let mut entries = load_entry_tables(&layers)?;
+    for official in official_releases()? {
+        // Pre-defined entries are shadowed by user-defined ones of the same name
+        if !entries.iter().any(|entry| entry.name() == official.name()) {
+            entries.push(official);
+        }
+    }
     Ok(entries)
 }
 
@@ -338,12 +1383,461 @@ pub fn load_entry(name: &str) -> Result<Entry> {
             }
         }
     }
-    Err(Error::InvalidEntry {
-        message: "Entry not found".into(),
-        name: name.into(),
+    Err(Error::EntryNotFound { name: name.into() })
+}
+
+/// Log files of one `Entry::build` invocation, stored in `{log_dir}/{timestamp}/{step}.log`
+struct BuildLog {
+    dir: PathBuf,
+    steps: Cell<usize>,
+    /// Print commands instead of running them
+    dry_run: bool,
+}
+
+impl BuildLog {
+    fn new(log_dir: &Path, dry_run: bool) -> Result<Self> {
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S%.3f").to_string();
+        let mut dir = log_dir.join(&stamp);
+        if !dry_run {
+            fs::create_dir_all(log_dir).with(log_dir)?;
+            // Runs started at the same time, e.g. `llvmenv test` just after a build, get distinct directories
+            let mut n = 0;
+            while let Err(e) = fs::create_dir(&dir) {
+                if e.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(e).with(&dir);
+                }
+                n += 1;
+                dir = log_dir.join(format!("{}-{}", stamp, n));
+            }
+            info!("Build logs are written into {}", dir.display());
+        }
+        Ok(BuildLog {
+            dir,
+            steps: Cell::new(0),
+            dry_run,
+        })
+    }
+
+    /// Print the command in dry-run, and returns if it is dry-run
+    fn dry_run(&self, cmd: &process::Command) -> bool {
+        if self.dry_run {
+            println!("{}", display_command(cmd));
+        }
+        self.dry_run
+    }
+
+    /// Create a build directory unless in dry-run
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        if !self.dry_run && !dir.exists() {
+            info!("Create build dir: {}", dir.display());
+            fs::create_dir_all(dir).with(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Run the command as a step of the build
+    fn run(&self, cmd: &mut process::Command, name: &str) -> Result<()> {
+        if self.dry_run(cmd) {
+            return Ok(());
+        }
+        cmd.check_run_logged(&self.step(name))
+    }
+
+    /// Log file for the next step, numbered to keep the order of steps
+    fn step(&self, name: &str) -> PathBuf {
+        let n = self.steps.get() + 1;
+        self.steps.set(n);
+        self.dir.join(format!("{:02}-{}.log", n, name))
+    }
+}
+
+/// Quote a word for POSIX shells and `shlex`, e.g. `LIT_OPTS` of lit
+fn shell_quote(s: &str) -> String {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || "'\"\\$;&|<>*?()".contains(c)) {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    } else {
+        s.into()
+    }
+}
+
+/// Shell-like representation of the command with its directory and environment variables
+fn display_command(cmd: &process::Command) -> String {
+    let quote = |s: &OsStr| shell_quote(&s.to_string_lossy());
+    let mut words = Vec::new();
+    if let Some(dir) = cmd.get_current_dir() {
+        words.push(format!("cd {} &&", quote(dir.as_os_str())));
+    }
+    let mut envs: Vec<_> = cmd.get_envs().collect();
+    envs.sort();
+    for (key, value) in envs {
+        if let Some(value) = value {
+            words.push(format!("{}={}", key.to_string_lossy(), quote(value)));
+        }
+    }
+    words.push(quote(cmd.get_program()));
+    words.extend(cmd.get_args().map(quote));
+    words.join(" ")
+}
+
+/// A cmake build of the entry, i.e. stage 1 of bootstrap build or the final build
+struct Stage<'a> {
+    /// Prefix of log files, e.g. `stage1-`
+    label: &'static str,
+    build_dir: PathBuf,
+    prefix: PathBuf,
+    build_type: BuildType,
+    target: &'a [String],
+    option: &'a HashMap<String, String>,
+    /// CMake cache files of the entry, i.e. `-C` options
+    cmake_cache: &'a [String],
+    /// Raw cmake arguments of the entry
+    cmake_args: &'a [String],
+    /// Compiler to build this stage
+    compiler: HostCompiler,
+    /// Additional cmake options set by llvmenv
+    extra: Vec<String>,
+    /// Targets to build. All targets are built if empty
+    targets: Vec<String>,
+    /// Targets to install, e.g. `install`. Not installed if empty
+    install: Vec<String>,
+    /// Hooks of the entry, only for the final stage
+    hooks: Option<&'a Hooks>,
+    /// Built for the machine where llvmenv runs, without the environment variables of the entry,
+    /// e.g. `CC` for the cross-compilation
+    native: bool,
+}
+
+impl Stage<'_> {
+    /// LTO is enabled by llvmenv or by user options
+    fn lto(&self) -> bool {
+        let enabled = |value: &str| {
+            !matches!(
+                value.to_ascii_uppercase().as_str(),
+                "OFF" | "FALSE" | "NO" | "0"
+            )
+        };
+        self.option
+            .get("LLVM_ENABLE_LTO")
+            .map(|value| enabled(value))
+            .or_else(|| {
+                self.extra
+                    .iter()
+                    .find_map(|opt| opt.strip_prefix("-DLLVM_ENABLE_LTO=").map(enabled))
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Parse memory size, e.g. `64G`, `512M`, or `1073741824` in bytes
+pub fn parse_memory_size(size: &str) -> Result<u64> {
+    let err = || Error::InvalidMemorySize { size: size.into() };
+    let s = size.trim().to_ascii_uppercase();
+    let s = s.trim_end_matches("IB").trim_end_matches('B');
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, ' '),
+    };
+    let shift = match unit {
+        ' ' => 0,
+        'K' => 10,
+        'M' => 20,
+        'G' => 30,
+        'T' => 40,
+        _ => return Err(err()),
+    };
+    let num: u64 = num.trim().parse().map_err(|_| err())?;
+    num.checked_mul(1 << shift).ok_or_else(err)
+}
+
+/// Available memory of the machine in bytes (Linux only)
+fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?;
+    let kb: u64 = line.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Number of parallel jobs in a stage
+///
+/// Compile and link jobs are limited by rough estimates of the memory used by a job,
+/// since linking LLVM tools requires several gigabytes per job, especially with debug info or LTO.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Jobs {
+    nproc: usize,
+    /// Limit of compile jobs, `None` if memory is unknown
+    compile: Option<usize>,
+    /// Limit of link jobs
+    link: usize,
+}
+
+impl Jobs {
+    fn new(nproc: usize, memory: Option<u64>, build_type: BuildType, lto: bool) -> Self {
+        const MIB: u64 = 1 << 20;
+        let compile = match build_type {
+            BuildType::Debug | BuildType::RelWithDebInfo => 1536,
+            BuildType::Release | BuildType::MinSizeRel => 1024,
+        };
+        let link = match (build_type, lto) {
+            (_, true) => 16384,
+            (BuildType::Debug, _) => 15360,
+            (BuildType::RelWithDebInfo, _) => 8192,
+            (BuildType::Release, _) | (BuildType::MinSizeRel, _) => 4096,
+        };
+        let limit = |per_job: u64| {
+            memory.map(|memory| ((memory / (per_job * MIB)) as usize).clamp(1, nproc.max(1)))
+        };
+        // Links are much fewer than compiles, and saturate disk I/O
+        let max_link = (nproc / 4).max(1);
+        Jobs {
+            nproc,
+            compile: limit(compile),
+            link: limit(link).map_or(max_link, |link| link.min(max_link)),
+        }
+    }
+
+    /// Options for cmake. LLVM supports the job pools only with Ninja.
+    fn option(&self, generator: &CMakeGenerator) -> Vec<String> {
+        let mut opts = Vec::new();
+        if !generator.is_ninja() {
+            return opts;
+        }
+        if let Some(compile) = self.compile.filter(|&compile| compile < self.nproc) {
+            opts.push(format!("-DLLVM_PARALLEL_COMPILE_JOBS={}", compile));
+        }
+        opts.push(format!("-DLLVM_PARALLEL_LINK_JOBS={}", self.link));
+        opts
+    }
+
+    /// Number of jobs for `cmake --build`. Other generators than Ninja are limited by compile jobs.
+    fn build_nproc(&self, generator: &CMakeGenerator) -> usize {
+        match self.compile {
+            Some(compile) if !generator.is_ninja() => compile.min(self.nproc),
+            _ => self.nproc,
+        }
+    }
+}
+
+const PGO_TRAINING_CPP: &str = include_str!("pgo-training.cpp");
+
+fn is_clang(cc: &Path) -> bool {
+    cc.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("clang"))
+}
+
+/// CMake options to use the compilers. llvm-ar next to clang is also used if exists.
+fn toolchain_options(cc: &Path, cxx: &Path) -> Vec<String> {
+    let mut opts = vec![
+        format!("-DCMAKE_C_COMPILER={}", cc.display()),
+        format!("-DCMAKE_CXX_COMPILER={}", cxx.display()),
+    ];
+    if !is_clang(cc) {
+        return opts;
+    }
+    let ar = cc.with_file_name("llvm-ar");
+    if ar.exists() {
+        opts.push(format!("-DCMAKE_AR={}", ar.display()));
+        opts.push(format!(
+            "-DCMAKE_RANLIB={}",
+            cc.with_file_name("llvm-ranlib").display()
+        ));
+    }
+    opts
+}
+
+/// Options of [Entry::build]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildOptions {
+    /// Number of parallel jobs
+    pub nproc: usize,
+    /// Print commands with their environment variables instead of running them
+    pub dry_run: bool,
+    /// Build even if the build is up to date
+    pub force: bool,
+}
+
+/// Suffixes of the build directories of intermediate stages
+const INTERMEDIATE_STAGES: [&str; 3] = ["stage1", "instrumented", "native"];
+
+/// The build directory and the directories of its intermediate stages
+pub(crate) fn with_stage_dirs(build_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![build_dir.to_path_buf()];
+    for stage in INTERMEDIATE_STAGES {
+        let mut name = build_dir.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-{}", stage));
+        paths.push(build_dir.with_file_name(name));
+    }
+    paths
+}
+
+const FINGERPRINT_FN: &str = ".llvmenv-fingerprint.toml";
+
+/// Fingerprint of the inputs of a build, stored in the build directory
+///
+/// The build is regarded as up to date if the fingerprint is not changed.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct Fingerprint {
+    /// Revisions of the source and tools, e.g. commit hash
+    source: Vec<String>,
+    /// Hashes of applied patches
+    patches: Vec<String>,
+    /// Version of the host compiler
+    compiler: String,
+    generator: CMakeGenerator,
+    build_type: BuildType,
+    /// Compiler launcher found in PATH
+    compiler_launcher: CompilerLauncher,
+    /// Linker of the entry or the global default
+    linker: Linker,
+    /// Expanded environment variables
+    env: Vec<(String, String)>,
+    /// Resolved setting of the entry
+    setting: String,
+}
+
+impl Fingerprint {
+    /// Read the fingerprint stored in `path`. `None` if not exists or in an old format.
+    fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(toml::from_str(&fs::read_to_string(path).with(path)?).ok())
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self).map_err(io::Error::other).with(path)?;
+        fs::write(path, content).with(path)?;
+        Ok(())
+    }
+}
+
+/// Revision of the source in `dir` by git or svn. Local changes are checked if `check_dirty`.
+pub(crate) fn source_revision(dir: &Path, check_dirty: bool) -> Option<String> {
+    let run = |program: &str, args: &[&str]| {
+        process::Command::new(program)
+            .args(args)
+            .current_dir(dir)
+            .check_output()
+            .ok()
+            .map(|(stdout, _)| stdout.trim().to_string())
+    };
+    if dir.join(".git").exists() {
+        let revision = run("git", &["rev-parse", "HEAD"])?;
+        if check_dirty
+            && !run("git", &["status", "--porcelain", "--untracked-files=no"])?.is_empty()
+        {
+            return None;
+        }
+        return Some(revision);
+    }
+    if dir.join(".svn").exists() {
+        // e.g. "1234", "1234M" for modified, "1234:1240" for mixed revisions
+        let revision = run("svnversion", &[])?;
+        if check_dirty && !revision.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        return Some(revision);
+    }
+    None
+}
+
+/// Short hash of the source revisions to install stage-1 compilers of different sources separately.
+/// `unversioned` if the revision is unknown, e.g. a local source with uncommitted changes.
+fn source_key(revisions: Option<&[String]>) -> String {
+    match revisions {
+        Some(revisions) => {
+            format!("{:x}", Sha256::digest(revisions.join("\n").as_bytes()))[..12].into()
+        }
+        None => "unversioned".into(),
+    }
+}
+
+/// Version of the compiler to detect its change
+fn compiler_version(compiler: &HostCompiler) -> Result<String> {
+    let cc = match compiler {
+        HostCompiler::System => PathBuf::from(env::var_os("CC").unwrap_or_else(|| "cc".into())),
+        HostCompiler::Build(name) => build_bin(name)?.join("clang"),
+        HostCompiler::Path(cc) => cc.clone(),
+    };
+    let version = process::Command::new(&cc)
+        .arg("--version")
+        .check_output()
+        .map(|(stdout, _)| stdout.lines().next().unwrap_or_default().to_string())
+        .unwrap_or_default();
+    Ok(format!("{}: {}", cc.display(), version))
+}
+
+/// Root of build directories, `$LLVMENV_BUILD_ROOT` or `$XDG_CACHE_HOME/llvmenv/builds`
+pub fn build_root() -> Result<PathBuf> {
+    build_root_from(env::var(BUILD_ROOT_ENV).ok())
+}
+
+/// Root of build directories with the value of `LLVMENV_BUILD_ROOT`
+fn build_root_from(root: Option<String>) -> Result<PathBuf> {
+    Ok(match root {
+        Some(root) if !root.is_empty() => PathBuf::from(shellexpand::tilde(&root).to_string()),
+        _ => cache_dir()?.join("builds"),
     })
 }
 
+/// Triple of the machine where llvmenv runs
+fn native_triple() -> Option<String> {
+    let (stdout, _) = process::Command::new("cc")
+        .arg("-dumpmachine")
+        .check_output()
+        .ok()?;
+    Some(stdout.trim().to_string())
+}
+
+/// `CMAKE_SYSTEM_NAME` for the triple
+fn cmake_system_name(triple: &str) -> &'static str {
+    if triple.contains("android") {
+        "Android"
+    } else if triple.contains("linux") {
+        "Linux"
+    } else if triple.contains("darwin") || triple.contains("apple") {
+        "Darwin"
+    } else if triple.contains("windows") || triple.contains("mingw") {
+        "Windows"
+    } else if triple.contains("freebsd") {
+        "FreeBSD"
+    } else {
+        "Generic"
+    }
+}
+
+/// Check if two triples are the same platform, e.g. `x86_64-linux-gnu` and `x86_64-pc-linux-gnu`
+fn same_platform(a: &str, b: &str) -> bool {
+    let arch = |triple: &str| match triple.split('-').next().unwrap_or("") {
+        "amd64" => "x86_64".to_string(),
+        "arm64" => "aarch64".to_string(),
+        arch => arch.to_string(),
+    };
+    arch(a) == arch(b) && cmake_system_name(a) == cmake_system_name(b)
+}
+
+/// CMake toolchain file for cross-compilation by clang
+fn toolchain_file(triple: &str) -> String {
+    format!(
+        r#"# Generated by llvmenv
+set(CMAKE_SYSTEM_NAME {system})
+set(CMAKE_SYSTEM_PROCESSOR {arch})
+set(CMAKE_C_COMPILER_TARGET {triple})
+set(CMAKE_CXX_COMPILER_TARGET {triple})
+set(CMAKE_ASM_COMPILER_TARGET {triple})
+set(CMAKE_FIND_ROOT_PATH_MODE_PROGRAM NEVER)
+set(CMAKE_FIND_ROOT_PATH_MODE_LIBRARY ONLY)
+set(CMAKE_FIND_ROOT_PATH_MODE_INCLUDE ONLY)
+set(CMAKE_FIND_ROOT_PATH_MODE_PACKAGE ONLY)
+"#,
+        system = cmake_system_name(triple),
+        arch = triple.split('-').next().unwrap_or(triple),
+        triple = triple,
+    )
+}
+
 lazy_static::lazy_static! {
     static ref LLVM_8_0_1: Version = Version::new(8, 0, 1);
     static ref LLVM_9_0_0: Version = Version::new(9, 0, 0);
@@ -351,7 +1845,7 @@ lazy_static::lazy_static! {
 
 impl Entry {
     /// Entry for official LLVM release
-    pub fn official(major: u64, minor: u64, patch: u64) -> Self {
+    pub fn official(major: u64, minor: u64, patch: u64) -> Result<Self> {
         let version = Version::new(major, minor, patch);
         let mut setting = EntrySetting::default();
 
@@ -415,7 +1909,7 @@ impl Entry {
             &format!("{}/openmp-{}.src.tar.xz", base_url, version),
         ));
         let name = version.to_string();
-        Entry::parse_setting(&name, Some(version), setting).unwrap()
+        Entry::parse_setting(&name, Some(version), setting)
     }
 
     fn parse_setting(name: &str, version: Option<Version>, setting: EntrySetting) -> Result<Self> {
@@ -429,10 +1923,14 @@ impl Entry {
             if !setting.tools.is_empty() {
                 warn!("'tools' must be used with URL, ignored");
             }
+            let path = shellexpand::full(&path).map_err(|e| Error::InvalidEntry {
+                name: name.into(),
+                message: format!("Cannot expand path '{}': {}", path, e),
+            })?;
             return Ok(Entry::Local {
                 name: name.into(),
                 version,
-                path: PathBuf::from(shellexpand::full(&path).unwrap().to_string()),
+                path: PathBuf::from(path.to_string()),
                 setting,
             });
         }
@@ -471,27 +1969,232 @@ impl Entry {
         Ok(())
     }
 
+    pub fn set_host_compiler(&mut self, compiler: HostCompiler) -> Result<()> {
+        self.setting_mut().host_compiler = compiler;
+        Ok(())
+    }
+
     pub fn set_build_type(&mut self, build_type: BuildType) -> Result<()> {
         self.setting_mut().build_type = build_type;
         Ok(())
     }
 
+    pub fn set_compiler_launcher(&mut self, launcher: CompilerLauncher) -> Result<()> {
+        self.setting_mut().compiler_launcher = Some(launcher);
+        Ok(())
+    }
+
+    pub fn set_linker(&mut self, linker: Linker) -> Result<()> {
+        self.setting_mut().linker = Some(linker);
+        Ok(())
+    }
+
+    pub fn set_max_memory(&mut self, size: &str) -> Result<()> {
+        parse_memory_size(size)?;
+        self.setting_mut().max_memory = Some(size.into());
+        Ok(())
+    }
+
+    /// Memory used to build, i.e. available memory capped by `max_memory`
+    fn memory_budget(&self) -> Result<Option<u64>> {
+        let max = match &self.setting().max_memory {
+            Some(size) => Some(parse_memory_size(size)?),
+            None => None,
+        };
+        Ok(match (available_memory(), max) {
+            (Some(available), Some(max)) => Some(available.min(max)),
+            (available, max) => available.or(max),
+        })
+    }
+
+    fn jobs(&self, stage: &Stage, nproc: usize) -> Result<Jobs> {
+        Ok(Jobs::new(
+            nproc,
+            self.memory_budget()?,
+            stage.build_type,
+            stage.lto(),
+        ))
+    }
+
+    /// Compiler launcher of the entry, or the global default
+    pub fn compiler_launcher(&self) -> Result<CompilerLauncher> {
+        Ok(match self.setting().compiler_launcher {
+            Some(launcher) => launcher,
+            None => GlobalConfig::load()?.compiler_launcher.unwrap_or_default(),
+        })
+    }
+
+    /// Linker of the entry, or the global default
+    pub fn linker(&self) -> Result<Linker> {
+        Ok(match self.setting().linker {
+            Some(linker) => linker,
+            None => GlobalConfig::load()?.linker.unwrap_or_default(),
+        })
+    }
+
+    /// Remote resources of the entry and tools with their directories relative to the source directory
+    fn resources(&self) -> Result<Vec<(String, Resource)>> {
+        let (url, tools) = match self {
+            Entry::Remote { url, tools, .. } => (url, tools),
+            Entry::Local { .. } => return Ok(Vec::new()),
+        };
+        let setting = self.setting();
+        let mut src = Resource::from_url(url)?;
+        let pin = match (&setting.rev, &setting.tag) {
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidEntry {
+                    name: self.name().into(),
+                    message: "Both `rev` and `tag` are specified".into(),
+                })
+            }
+            (Some(rev), None) => Some(commit_sha(rev)?),
+            (None, Some(tag)) => Some(format!("refs/tags/{}", tag)),
+            (None, None) => None,
+        };
+        if let Some(pin) = pin {
+            match &mut src {
+                Resource::Git { branch, rev, .. } => {
+                    *branch = None;
+                    *rev = Some(pin);
+                }
+                _ => {
+                    return Err(Error::InvalidEntry {
+                        name: self.name().into(),
+                        message: "`rev` and `tag` are only for git repositories".into(),
+                    })
+                }
+            }
+        }
+        let mut resources = vec![(String::new(), src)];
+        for tool in tools {
+            resources.push((tool.rel_path()?, Resource::from_url(&tool.url)?));
+        }
+        Ok(resources)
+    }
+
+    /// Download or update the remote resources. Patches are applied again if the revision is changed.
+    fn sync_sources(&self, update: bool) -> Result<()> {
+        let src_dir = self.src_dir()?;
+        for (dir, src) in self.resources()? {
+            let path = src_dir.join(&dir);
+            let before = source_revision(&path, false);
+            if update {
+                src.update(&path)?;
+            } else {
+                src.download(&path)?;
+            }
+            let after = source_revision(&path, false);
+            if let Some(revision) = &after {
+                info!("Source of {} is at {}", self.name(), revision);
+            }
+            if before.is_some() && before != after {
+                self.forget_patches(&dir)?;
+            }
+        }
+        self.apply_patches()
+    }
+
     pub fn checkout(&self) -> Result<()> {
-        match self {
-            Entry::Remote { url, tools, .. } => {
-                let src = Resource::from_url(url)?;
-                src.download(&self.src_dir()?)?;
-                for tool in tools {
-                    let path = self.src_dir()?.join(tool.rel_path());
-                    let src = Resource::from_url(&tool.url)?;
-                    src.download(&path)?;
+        self.sync_sources(false)
+    }
+
+    /// Patches applied to the source
+    pub fn applied_patches(&self) -> Result<Vec<AppliedPatch>> {
+        let stamp = self.src_dir()?.join(PATCH_STAMP_FN);
+        if !stamp.exists() {
+            return Ok(Vec::new());
+        }
+        let stamp: PatchStamp = toml::from_str(&fs::read_to_string(&stamp).with(&stamp)?)?;
+        Ok(stamp.patches)
+    }
+
+    /// Apply patches of the entry and tools. Patches already applied are skipped.
+    /// Patches of URL are identified by the URL not to download them again, e.g. offline.
+    pub(crate) fn apply_patches(&self) -> Result<()> {
+        let setting = self.setting();
+        let level = setting.patch_level.unwrap_or(1);
+        let mut patch_sets = vec![(String::new(), level, &setting.patches)];
+        if let Entry::Remote { tools, .. } = self {
+            for tool in tools {
+                patch_sets.push((
+                    tool.rel_path()?,
+                    tool.patch_level.unwrap_or(level),
+                    &tool.patches,
+                ));
+            }
+        }
+        let src_dir = self.src_dir()?;
+        let mut applied = self.applied_patches()?;
+        for (dir, level, patches) in patch_sets {
+            for patch in patches {
+                let content = if is_url(patch) {
+                    if applied.iter().any(|p| p.dir == dir && p.patch == *patch) {
+                        continue;
+                    }
+                    fetch(patch)?
+                } else {
+                    let path = PathBuf::from(shellexpand::tilde(patch).to_string());
+                    fs::read(&path).with(&path)?
+                };
+                let sha256 = format!("{:x}", Sha256::digest(&content));
+                if applied.iter().any(|p| p.dir == dir && p.sha256 == sha256) {
+                    continue;
                 }
+                info!("Apply patch: {}", patch);
+                apply_patch(patch, &content, &src_dir.join(&dir), level)?;
+                applied.push(AppliedPatch {
+                    patch: patch.clone(),
+                    dir: dir.clone(),
+                    sha256,
+                });
+                self.write_patch_stamp(&applied)?;
             }
-            Entry::Local { .. } => {}
         }
         Ok(())
     }
 
+    /// Forget patches applied to a directory whose source has been checked out again
+    pub(crate) fn forget_patches(&self, dir: &str) -> Result<()> {
+        let mut applied = self.applied_patches()?;
+        let len = applied.len();
+        applied.retain(|p| p.dir != dir);
+        if applied.len() != len {
+            self.write_patch_stamp(&applied)?;
+        }
+        Ok(())
+    }
+
+    fn write_patch_stamp(&self, patches: &[AppliedPatch]) -> Result<()> {
+        let stamp = self.src_dir()?.join(PATCH_STAMP_FN);
+        let content = toml::to_string(&PatchStamp {
+            patches: patches.to_vec(),
+        })
+        .map_err(io::Error::other)
+        .with(&stamp)?;
+        fs::write(&stamp, content).with(&stamp)?;
+        Ok(())
+    }
+
+    /// Fetch the whole history of the git source, which is fetched shallowly by default.
+    /// Local sources are never modified, and it is an error if they are shallow.
+    pub fn unshallow(&self) -> Result<()> {
+        let src_dir = self.src_dir()?;
+        let invalid = |message: &str| Error::InvalidEntry {
+            name: self.name().into(),
+            message: message.into(),
+        };
+        match self {
+            Entry::Remote { .. } => match self.resources()?.remove(0).1 {
+                Resource::Git { .. } => unshallow(&src_dir),
+                _ => Err(invalid("Source is not a git repository")),
+            },
+            Entry::Local { .. } if is_shallow(&src_dir)? => Err(invalid(
+                "Local source is a shallow clone. Fetch the whole history by `git fetch --unshallow`",
+            )),
+            Entry::Local { .. } => Ok(()),
+        }
+    }
+
     pub fn clean_cache_dir(&self) -> Result<()> {
         let path = self.src_dir()?;
         info!("Remove cache dir: {}", path.display());
@@ -500,18 +2203,7 @@ impl Entry {
     }
 
     pub fn update(&self) -> Result<()> {
-        match self {
-            Entry::Remote { url, tools, .. } => {
-                let src = Resource::from_url(url)?;
-                src.update(&self.src_dir()?)?;
-                for tool in tools {
-                    let src = Resource::from_url(&tool.url)?;
-                    src.update(&self.src_dir()?.join(tool.rel_path()))?;
-                }
-            }
-            Entry::Local { .. } => {}
-        }
-        Ok(())
+        self.sync_sources(true)
     }
 
     pub fn name(&self) -> &str {
@@ -535,8 +2227,11 @@ impl Entry {
         })
     }
 
+    /// Build directory. It is `$LLVMENV_BUILD_ROOT/{install_name}` by default
+    /// (`$XDG_CACHE_HOME/llvmenv/builds` if `LLVMENV_BUILD_ROOT` is not set),
+    /// and keyed by the install name not to share CMakeCache between variants.
     pub fn build_dir(&self) -> Result<PathBuf> {
-        let dir = self.src_dir()?.join("build");
+        let dir = self.build_dir_path()?;
         if !dir.exists() {
             info!("Create build dir: {}", dir.display());
             fs::create_dir_all(&dir).with(&dir)?;
@@ -544,138 +2239,2142 @@ impl Entry {
         Ok(dir)
     }
 
+    /// Build directory without creating it
+    pub(crate) fn build_dir_path(&self) -> Result<PathBuf> {
+        match &self.setting().build_dir {
+            Some(dir) => self.expand_path(dir),
+            None => self.default_build_dir(&build_root()?),
+        }
+    }
+
+    /// Build directory in the root of build directories if `build_dir` is not set
+    fn default_build_dir(&self, root: &Path) -> Result<PathBuf> {
+        Ok(root.join(self.install_name()?))
+    }
+
+    /// Build directory in the source tree used by old llvmenv, if it is left
+    pub(crate) fn legacy_build_dir(&self) -> Result<Option<PathBuf>> {
+        let dir = self.src_dir()?.join("build");
+        Ok(Some(dir).filter(|dir| {
+            dir.join("CMakeCache.txt").exists()
+                && self
+                    .build_dir_path()
+                    .map_or(true, |build_dir| build_dir != *dir)
+        }))
+    }
+
+    /// Remove the build directory, and the directories of intermediate stages
     pub fn clean_build_dir(&self) -> Result<()> {
-        let path = self.build_dir()?;
-        info!("Remove build dir: {}", path.display());
-        fs::remove_dir_all(&path).with(&path)?;
+        let paths = with_stage_dirs(&self.build_dir_path()?);
+        for path in paths.iter().filter(|path| path.exists()) {
+            info!("Remove build dir: {}", path.display());
+            fs::remove_dir_all(path).with(path)?;
+        }
         Ok(())
     }
 
     pub fn prefix(&self) -> Result<PathBuf> {
-        Ok(data_dir()?.join(self.name()))
-    }
-
-    pub fn build(&self, nproc: usize) -> Result<()> {
-        self.configure()?;
-        process::Command::new("cmake")
-            .args(&[
-                "--build",
-                &format!("{}", self.build_dir()?.display()),
-                "--target",
-                "install",
-            ])
-            .args(
-                &self
-                    .setting()
-                    .generator
-                    .build_option(nproc, self.setting().build_type),
-            )
-            .check_run()?;
+        Ok(data_dir()?.join(self.install_name()?))
+    }
+
+    fn install_name_template(&self) -> &str {
+        match (&self.setting().install_name, &self.setting().host_triple) {
+            (Some(template), _) => template,
+            (None, Some(_)) => "{name}-{triple}",
+            (None, None) => "{name}",
+        }
+    }
+
+    /// Name of the build installed by this entry, expanded from `install_name` template
+    pub fn install_name(&self) -> Result<String> {
+        let template = self.install_name_template();
+        let mut install_name = template
+            .replace("{name}", self.name())
+            .replace("{build_type}", &format!("{:?}", self.setting().build_type));
+        if install_name.contains("{triple}") {
+            let triple = match &self.setting().host_triple {
+                Some(triple) => triple.clone(),
+                None => native_triple().ok_or_else(|| Error::InvalidEntry {
+                    name: self.name().into(),
+                    message: "Cannot detect the native triple for {triple}".into(),
+                })?,
+            };
+            install_name = install_name.replace("{triple}", &triple);
+        }
+        if install_name.contains(['{', '}', '/']) || install_name.is_empty() {
+            return Err(Error::InvalidEntry {
+                name: self.name().into(),
+                message: format!("Invalid install_name: {}", template),
+            });
+        }
+        Ok(install_name)
+    }
+
+    pub fn set_install_name(&mut self, template: &str) -> Result<()> {
+        self.setting_mut().install_name = Some(template.into());
+        self.install_name()?;
         Ok(())
     }
 
-    fn configure(&self) -> Result<()> {
-        let setting = self.setting();
-        let mut opts = setting.generator.option();
-        opts.push(format!("{}", self.src_dir()?.display()));
+    /// Append `-{suffix}` to the install name
+    pub fn set_install_suffix(&mut self, suffix: &str) -> Result<()> {
+        let template = format!("{}-{}", self.install_name_template(), suffix);
+        self.set_install_name(&template)
+    }
 
-        opts.push(format!(
-            "-DCMAKE_INSTALL_PREFIX={}",
-            data_dir()?.join(self.prefix()?).display()
-        ));
-        opts.push(format!("-DCMAKE_BUILD_TYPE={:?}", setting.build_type));
+    /// Overwrite the build directory
+    pub fn set_build_dir(&mut self, dir: &Path) -> Result<()> {
+        self.setting_mut().build_dir = Some(dir.display().to_string());
+        Ok(())
+    }
+
+    /// Host triple if this entry is cross-compiled
+    pub fn cross_triple(&self) -> Result<Option<&str>> {
+        let triple = match self.setting().host_triple.as_deref() {
+            Some(triple) => triple,
+            None => return Ok(None),
+        };
+        let native = native_triple().ok_or_else(|| Error::InvalidEntry {
+            name: self.name().into(),
+            message: format!("Cannot detect the native triple to compare with {}", triple),
+        })?;
+        Ok(Some(triple).filter(|triple| !same_platform(&native, triple)))
+    }
+
+    /// Expand path in setting. Relative path is resolved against the source directory.
+    fn expand_path(&self, path: &str) -> Result<PathBuf> {
+        let path = PathBuf::from(shellexpand::tilde(path).to_string());
+        Ok(if path.is_relative() {
+            self.src_dir()?.join(path)
+        } else {
+            path
+        })
+    }
+
+    /// Directory where build logs of this entry are stored
+    pub fn log_dir(&self) -> Result<PathBuf> {
+        Ok(cache_dir()?.join(self.name()).join("logs"))
+    }
 
-        // Enable ccache if exists
-        if which::which("ccache").is_ok() {
-            opts.push("-DLLVM_CCACHE_BUILD=ON".into());
+    /// Log directories of past builds, the oldest comes first.
+    /// Each directory contains log files of configure, build, and install steps.
+    pub fn logs(&self) -> Result<Vec<PathBuf>> {
+        let dir = self.log_dir()?;
+        if !dir.exists() {
+            return Ok(Vec::new());
         }
+        let mut logs = Vec::new();
+        for entry in fs::read_dir(&dir).with(&dir)? {
+            let path = entry.with(&dir)?.path();
+            if path.is_dir() {
+                logs.push(path);
+            }
+        }
+        logs.sort();
+        Ok(logs)
+    }
 
-        // Enable lld if exists
-        if which::which("lld").is_ok() {
-            opts.push("-DLLVM_ENABLE_LLD=ON".into());
+    /// Setting of bootstrap build, which is also required for PGO and ThinLTO
+    fn bootstrap(&self) -> Option<BootstrapSetting> {
+        let setting = self.setting();
+        match (&setting.bootstrap, setting.optimize) {
+            (None, Optimize::None) => None,
+            (bootstrap, _) => Some(bootstrap.clone().unwrap_or_default()),
         }
+    }
 
-        // Target architectures
-        if !setting.target.is_empty() {
-            opts.push(format!(
-                "-DLLVM_TARGETS_TO_BUILD={}",
-                setting.target.iter().join(";")
-            ));
+    /// Prefix where the stage-1 compiler of bootstrap build is installed
+    pub fn stage1_prefix(&self) -> Result<Option<PathBuf>> {
+        Ok(match &self.bootstrap() {
+            Some(bootstrap) => {
+                let name = match &bootstrap.name {
+                    Some(name) => name.clone(),
+                    None => format!("{}-stage1", self.name()),
+                };
+                Some(cache_dir()?.join("stage1").join(name))
+            }
+            None => None,
+        })
+    }
+
+    /// Build directory of an intermediate stage, next to the build directory.
+    /// It is created unless in dry-run.
+    fn stage_build_dir(&self, stage: &str, log: &BuildLog) -> Result<PathBuf> {
+        let build_dir = self.build_dir_path()?;
+        let mut name = build_dir.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-{}", stage));
+        let dir = build_dir.with_file_name(name);
+        log.create_dir(&dir)?;
+        Ok(dir)
+    }
+
+    /// Revisions of the source and tools. `None` if the revision cannot be determined,
+    /// e.g. a local source with uncommitted changes.
+    fn source_revisions(&self) -> Result<Option<Vec<String>>> {
+        let src_dir = self.src_dir()?;
+        let mut sources = vec![(src_dir.clone(), self.url())];
+        if let Entry::Remote { tools, .. } = self {
+            for tool in tools {
+                sources.push((src_dir.join(tool.rel_path()?), Some(tool.url.as_str())));
+            }
+        }
+        let local = matches!(self, Entry::Local { .. });
+        let mut revisions = Vec::new();
+        for (dir, url) in sources {
+            match source_revision(&dir, local).or_else(|| url.map(String::from)) {
+                Some(revision) => revisions.push(revision),
+                None => return Ok(None),
+            }
         }
+        Ok(Some(revisions))
+    }
 
-        // Other options
-        for (k, v) in &setting.option {
-            opts.push(format!("-D{}={}", k, v));
+    /// Fingerprint of the build, `None` if the source revision is unknown
+    fn fingerprint(&self) -> Result<Option<Fingerprint>> {
+        let setting = self.setting();
+        self.fingerprint_of(setting.build_type, setting)
+    }
+
+    /// Fingerprint of a stage built from the source of the entry with `setting`
+    fn fingerprint_of(
+        &self,
+        build_type: BuildType,
+        setting: &impl serde::Serialize,
+    ) -> Result<Option<Fingerprint>> {
+        let revisions = match self.source_revisions()? {
+            Some(revisions) => revisions,
+            None => return Ok(None),
+        };
+        let entry = self.setting();
+        Ok(Some(Fingerprint {
+            source: revisions,
+            patches: self
+                .applied_patches()?
+                .into_iter()
+                .map(|patch| patch.sha256)
+                .collect(),
+            compiler: compiler_version(&entry.host_compiler)?,
+            generator: entry.generator.clone(),
+            build_type,
+            compiler_launcher: self.compiler_launcher()?.resolve(),
+            linker: self.linker()?,
+            env: self.env()?,
+            // Serialize via Value, since EntrySetting has tables before values
+            setting: toml::Value::try_from(setting)
+                .and_then(|value| toml::to_string(&value))
+                .map_err(|e| Error::InvalidEntry {
+                    name: self.name().into(),
+                    message: e.to_string(),
+                })?,
+        }))
+    }
+
+    fn url(&self) -> Option<&str> {
+        match self {
+            Entry::Remote { url, .. } => Some(url),
+            Entry::Local { .. } => None,
         }
+    }
 
-        process::Command::new("cmake")
-            .args(&opts)
-            .current_dir(self.build_dir()?)
-            .check_run()?;
+    /// Build the entry. Nothing is done if the build is up to date.
+    pub fn build(&self, opts: &BuildOptions) -> Result<()> {
+        let fingerprint_path = self.build_dir_path()?.join(FINGERPRINT_FN);
+        let fingerprint = self.fingerprint()?;
+        let last = Fingerprint::read(&fingerprint_path)?;
+        if let (Some(current), Some(last)) = (&fingerprint, &last) {
+            if !opts.force && current == last && self.prefix()?.join(METADATA_FN).exists() {
+                info!("{} is up to date", self.install_name()?);
+                return Ok(());
+            }
+            if current.generator != last.generator || current.build_type != last.build_type {
+                info!("Generator or build type is changed. Clean build directory.");
+                if !opts.dry_run {
+                    self.clean_build_dir()?;
+                }
+            }
+        }
+        if opts.dry_run {
+            return self.run_build(opts.nproc, true);
+        }
+        if fingerprint_path.exists() {
+            fs::remove_file(&fingerprint_path).with(&fingerprint_path)?;
+        }
+        self.run_build(opts.nproc, false)?;
+        if let Some(fingerprint) = fingerprint {
+            fingerprint.write(&fingerprint_path)?;
+        }
         Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn parse_url() {
-        let setting = EntrySetting {
-            url: Some("http://llvm.org/svn/llvm-project/llvm/trunk".into()),
-            ..Default::default()
+    /// Run lit test suites (check targets, e.g. `check-clang`) in the build directory,
+    /// and store their summaries in the metadata of the build
+    pub fn test(&self, suites: &[String], nproc: usize) -> Result<Vec<TestSummary>> {
+        let name = self.install_name()?;
+        let build = Build::from_name(&name)?;
+        let build_dir = self.build_dir_path()?;
+        let mut metadata = match build.metadata()? {
+            Some(metadata) if build_dir.join("CMakeCache.txt").exists() => metadata,
+            _ => return Err(Error::BuildNotFound { name }),
         };
-        let _entry = Entry::parse_setting("url", None, setting).unwrap();
+        let setting = self.setting();
+        let build_option = setting.generator.build_option(nproc, setting.build_type);
+        let log = BuildLog::new(&self.log_dir()?, false)?;
+        let mut summaries = Vec::new();
+        for suite in suites {
+            let output = log.dir.join(format!("{}.json", suite));
+            let lit_opts = self
+                .env()?
+                .into_iter()
+                .find(|(key, _)| key == "LIT_OPTS")
+                .map(|(_, value)| value)
+                .or_else(|| env::var("LIT_OPTS").ok());
+            // lit splits LIT_OPTS like a shell
+            let output_opt = format!("--output={}", shell_quote(&output.to_string_lossy()));
+            let lit_opts = match lit_opts {
+                Some(opts) => format!("{} {}", opts, output_opt),
+                None => output_opt,
+            };
+            info!("Run test suite: {}", suite);
+            let result = log.run(
+                self.command("cmake")?
+                    .arg("--build")
+                    .arg(&build_dir)
+                    .args(["--target", suite])
+                    .args(&build_option)
+                    .env("LIT_OPTS", lit_opts),
+                &format!("test-{}", suite),
+            );
+            if !output.exists() {
+                // Failed before running lit, e.g. unknown target or build error
+                result?;
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "lit does not write the results",
+                ))
+                .with(&output);
+            }
+            let summary =
+                TestSummary::from_lit_json(suite, &fs::read_to_string(&output).with(&output)?)
+                    .map_err(io::Error::other)
+                    .with(&output)?;
+            if summary.failed == 0 {
+                result?;
+            }
+            metadata.tests.retain(|test| &test.suite != suite);
+            metadata.tests.push(summary.clone());
+            metadata.write(build.prefix())?;
+            summaries.push(summary);
+        }
+        Ok(summaries)
     }
 
-    #[test]
-    fn parse_path() {
-        let setting = EntrySetting {
-            path: Some("~/.config/llvmenv".into()),
-            ..Default::default()
+    /// Run all stages of the build. Commands are only printed if `dry_run` is set.
+    fn run_build(&self, nproc: usize, dry_run: bool) -> Result<()> {
+        let log = BuildLog::new(&self.log_dir()?, dry_run)?;
+        let setting = self.setting();
+        let build_dir = self.build_dir_path()?;
+        log.create_dir(&build_dir)?;
+        let stage1 = self.build_stage1(nproc, &log)?;
+        let mut extra = Vec::new();
+        if let (true, Some(stage1)) = (setting.optimize.pgo(), &stage1) {
+            let profdata = self.build_profile(stage1, nproc, &log)?;
+            extra.push(format!("-DLLVM_PROFDATA_FILE={}", profdata.display()));
+        }
+        let linker = self.linker()?.resolve(stage1.as_deref());
+        let compiler = match stage1 {
+            Some(stage1) => HostCompiler::Path(stage1.join("bin").join("clang")),
+            None => setting.host_compiler.clone(),
         };
-        let _entry = Entry::parse_setting("path", None, setting).unwrap();
+        if setting.optimize.thinlto() {
+            extra.push("-DLLVM_ENABLE_LTO=Thin".into());
+        }
+        extra.append(&mut self.cross_options(&compiler, nproc, &log)?);
+        if !setting.distribution_components.is_empty() {
+            extra.push(format!(
+                "-DLLVM_DISTRIBUTION_COMPONENTS={}",
+                setting.distribution_components.iter().join(";")
+            ));
+        }
+        let (targets, install) = self.install_targets();
+        self.build_stage(
+            &Stage {
+                label: "",
+                build_dir,
+                prefix: self.prefix()?,
+                build_type: setting.build_type,
+                target: &setting.target,
+                option: &setting.option,
+                cmake_cache: &setting.cmake_cache,
+                cmake_args: &setting.cmake_args,
+                compiler,
+                extra,
+                targets,
+                install,
+                hooks: Some(&setting.hooks),
+                native: false,
+            },
+            nproc,
+            &log,
+        )?;
+        if dry_run {
+            return Ok(());
+        }
+        BuildMetadata {
+            entry: self.name().into(),
+            host_triple: setting.host_triple.clone(),
+            compiler_launcher: Some(self.compiler_launcher()?.resolve()),
+            linker: Some(linker),
+            revision: source_revision(&self.src_dir()?, false),
+            patches: self.applied_patches()?,
+            tests: Vec::new(),
+        }
+        .write(&self.prefix()?)?;
+        Build::from_path(&self.prefix()?).record_use()
     }
 
-    #[should_panic]
-    #[test]
-    fn parse_no_entry() {
-        let setting = EntrySetting::default();
-        let _entry = Entry::parse_setting("no_entry", None, setting).unwrap();
+    /// CMake options for cross-compilation. Tablegen for the native machine is built if required.
+    fn cross_options(
+        &self,
+        compiler: &HostCompiler,
+        nproc: usize,
+        log: &BuildLog,
+    ) -> Result<Vec<String>> {
+        let setting = self.setting();
+        let mut opts = Vec::new();
+        if let Some(sysroot) = &setting.sysroot {
+            opts.push(format!("-DCMAKE_SYSROOT={}", shellexpand::tilde(sysroot)));
+        }
+        if let Some(file) = &setting.cmake_toolchain_file {
+            opts.push(format!(
+                "-DCMAKE_TOOLCHAIN_FILE={}",
+                self.expand_path(file)?.display()
+            ));
+        }
+        let triple = match self.cross_triple()? {
+            Some(triple) => triple,
+            None => return Ok(opts),
+        };
+        // Generated toolchain file only sets the target of the compiler, which works only for clang
+        if setting.cmake_toolchain_file.is_none() && compiler.clang()?.is_none() {
+            return Err(Error::InvalidEntry {
+                name: self.name().into(),
+                message: format!(
+                    "Cross-compilation for {} requires clang as host_compiler, or cmake_toolchain_file",
+                    triple
+                ),
+            });
+        }
+        if setting.optimize.pgo() {
+            return Err(Error::InvalidEntry {
+                name: self.name().into(),
+                message: "PGO is not supported in cross-compilation".into(),
+            });
+        }
+        info!("Cross-compile for {}", triple);
+        if setting.cmake_toolchain_file.is_none() {
+            let file = self.build_dir_path()?.join("llvmenv-toolchain.cmake");
+            if !log.dry_run {
+                fs::write(&file, toolchain_file(triple)).with(&file)?;
+            }
+            opts.push(format!("-DCMAKE_TOOLCHAIN_FILE={}", file.display()));
+        }
+        opts.push(format!("-DLLVM_HOST_TRIPLE={}", triple));
+        opts.push(format!("-DLLVM_DEFAULT_TARGET_TRIPLE={}", triple));
+        opts.append(&mut self.build_native_tablegen(nproc, log)?);
+        Ok(opts)
     }
 
-    #[should_panic]
-    #[test]
-    fn parse_duplicated() {
-        let setting = EntrySetting {
-            url: Some("http://llvm.org/svn/llvm-project/llvm/trunk".into()),
-            path: Some("~/.config/llvmenv".into()),
-            ..Default::default()
+    /// Build tablegen for the native machine by the system compiler, and returns options to use them
+    fn build_native_tablegen(&self, nproc: usize, log: &BuildLog) -> Result<Vec<String>> {
+        let setting = self.setting();
+        let build_dir = self.stage_build_dir("native", log)?;
+        // Projects are required to build clang-tblgen in monorepo
+        let mut option = HashMap::new();
+        let projects = setting.option.get("LLVM_ENABLE_PROJECTS");
+        if let Some(projects) = projects {
+            option.insert("LLVM_ENABLE_PROJECTS".to_string(), projects.clone());
+        }
+        let stage = Stage {
+            label: "native-",
+            build_dir: build_dir.clone(),
+            prefix: build_dir.join("install"),
+            build_type: BuildType::Release,
+            target: &setting.target,
+            option: &option,
+            cmake_cache: &[],
+            cmake_args: &[],
+            compiler: HostCompiler::System,
+            extra: Vec::new(),
+            targets: Vec::new(),
+            install: Vec::new(),
+            hooks: None,
+            native: true,
         };
-        let _entry = Entry::parse_setting("duplicated", None, setting).unwrap();
+        let jobs = self.jobs(&stage, nproc)?;
+        self.configure(&stage, &jobs, log)?;
+        let nproc = jobs.build_nproc(&setting.generator);
+
+        let mut tablegens = vec![("LLVM_TABLEGEN", "llvm-tblgen")];
+        if self.src_dir()?.join("tools").join("clang").exists()
+            || projects.is_some_and(|p| p.split(';').any(|p| p == "clang"))
+        {
+            tablegens.push(("CLANG_TABLEGEN", "clang-tblgen"));
+        }
+        let bin_dir = setting.generator.bin_dir(&build_dir, stage.build_type);
+        let mut opts = Vec::new();
+        for (var, tablegen) in tablegens {
+            log.run(
+                self.stage_command(&stage, "cmake")?
+                    .arg("--build")
+                    .arg(&build_dir)
+                    .args(["--target", tablegen])
+                    .args(setting.generator.build_option(nproc, stage.build_type)),
+                &format!("native-{}", tablegen),
+            )?;
+            opts.push(format!(
+                "-D{}={}",
+                var,
+                bin_dir
+                    .join(format!("{}{}", tablegen, env::consts::EXE_SUFFIX))
+                    .display()
+            ));
+        }
+        Ok(opts)
     }
 
-    #[test]
-    fn parse_with_version() {
-        let path = "~/.config/llvmenv";
-        let version = Version::new(10, 0, 0);
-        let setting = EntrySetting {
-            path: Some(path.into()),
-            ..Default::default()
+    /// Targets to build and install in the final stage
+    ///
+    /// - `distribution_components`: `distribution` and `install-distribution`
+    /// - `install_targets`: each component and `install-{component}`
+    /// - otherwise: all targets and `install`
+    ///
+    /// The `-stripped` variants (or `install/strip`) are used if `strip` is set.
+    fn install_targets(&self) -> (Vec<String>, Vec<String>) {
+        let setting = self.setting();
+        let suffix = if setting.strip { "-stripped" } else { "" };
+        let mut targets = Vec::new();
+        let mut install = Vec::new();
+        if !setting.distribution_components.is_empty() {
+            targets.push("distribution".to_string());
+            install.push(format!("install-distribution{}", suffix));
+        }
+        for component in &setting.install_targets {
+            targets.push(component.clone());
+            install.push(format!("install-{}{}", component, suffix));
+        }
+        if install.is_empty() {
+            install.push(
+                if setting.strip {
+                    "install/strip"
+                } else {
+                    "install"
+                }
+                .into(),
+            );
+        }
+        (targets, install)
+    }
+
+    /// Build stage-1 compiler if required, and returns its prefix
+    fn build_stage1(&self, nproc: usize, log: &BuildLog) -> Result<Option<PathBuf>> {
+        let setting = self.setting();
+        let (bootstrap, shared) = match (self.bootstrap(), self.stage1_prefix()?) {
+            (Some(bootstrap), Some(shared)) => (bootstrap, shared),
+            _ => return Ok(None),
         };
-        let entry = Entry::parse_setting("path", Some(version.clone()), setting.clone()).unwrap();
+        // Entries of different sources sharing the name do not rebuild the stage-1 compiler of each other
+        let prefix = shared.join(source_key(self.source_revisions()?.as_deref()));
+        // Another llvmenv process may build the same stage-1 compiler
+        let _lock = if log.dry_run {
+            None
+        } else {
+            fs::create_dir_all(&shared).with(&shared)?;
+            Some(FileLock::acquire(&prefix)?)
+        };
+        let bootstrap = self.stage1_setting(bootstrap);
+        let clang = prefix.join("bin").join("clang");
+        // Shared stage-1 compiler is reused only if it is built from the same source and setting
+        let fingerprint_path = prefix.join(FINGERPRINT_FN);
+        let fingerprint = self.fingerprint_of(bootstrap.build_type, &bootstrap)?;
+        if clang.exists()
+            && fingerprint.is_some()
+            && Fingerprint::read(&fingerprint_path)? == fingerprint
+        {
+            info!("Use existing stage-1 compiler: {}", prefix.display());
+            return Ok(Some(prefix));
+        }
+        info!("Build stage-1 compiler: {}", prefix.display());
+        self.build_stage(
+            &Stage {
+                label: "stage1-",
+                build_dir: self.stage_build_dir("stage1", log)?,
+                prefix: prefix.clone(),
+                build_type: bootstrap.build_type,
+                target: &bootstrap.target,
+                option: &bootstrap.option,
+                cmake_cache: &[],
+                cmake_args: &[],
+                compiler: setting.host_compiler.clone(),
+                extra: Vec::new(),
+                targets: Vec::new(),
+                install: vec!["install".into()],
+                hooks: None,
+                native: false,
+            },
+            nproc,
+            log,
+        )?;
+        if log.dry_run {
+            return Ok(Some(prefix));
+        }
+        let mut required = vec![clang];
+        if self.setting().optimize.thinlto() {
+            required.push(prefix.join("bin").join("ld.lld"));
+        }
+        if let Some(path) = required.into_iter().find(|path| !path.exists()) {
+            return Err(Error::Stage1NotBuilt { path });
+        }
+        if let Some(fingerprint) = fingerprint {
+            fingerprint.write(&fingerprint_path)?;
+        }
+        Ok(Some(prefix))
+    }
 
-        assert_eq!(entry.version(), Some(&version));
-        assert_eq!(
-            entry,
-            Entry::Local {
-                name: "path".into(),
-                version: Some(version),
-                path: PathBuf::from(shellexpand::full(path).unwrap().to_string()),
-                setting,
+    /// Setting of the stage-1 compiler with the target and options resolved.
+    /// Clang (and lld and compiler-rt for `optimize`) is added to `LLVM_ENABLE_PROJECTS` of monorepo,
+    /// i.e. if the entry or stage 1 sets it.
+    fn stage1_setting(&self, mut bootstrap: BootstrapSetting) -> BootstrapSetting {
+        let setting = self.setting();
+        if bootstrap.target.is_empty() {
+            bootstrap.target = setting.target.clone();
+        }
+        const PROJECTS: &str = "LLVM_ENABLE_PROJECTS";
+        if bootstrap.option.contains_key(PROJECTS) || setting.option.contains_key(PROJECTS) {
+            let mut projects: Vec<String> = bootstrap
+                .option
+                .get(PROJECTS)
+                .map(|projects| {
+                    projects
+                        .split(';')
+                        .filter(|p| !p.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let mut required = vec!["clang"];
+            if setting.optimize != Optimize::None {
+                // Profile runtime for PGO, and linker for ThinLTO
+                required.extend(["lld", "compiler-rt"]);
             }
-        )
+            for required in required {
+                if !projects.iter().any(|p| p == required || p == "all") {
+                    projects.push(required.to_string());
+                }
+            }
+            bootstrap
+                .option
+                .insert(PROJECTS.to_string(), projects.join(";"));
+        }
+        bootstrap
+    }
+
+    /// Build instrumented clang by the stage-1 compiler, train it, and returns the merged profile
+    fn build_profile(&self, stage1: &Path, nproc: usize, log: &BuildLog) -> Result<PathBuf> {
+        let setting = self.setting();
+        let build_dir = self.stage_build_dir("instrumented", log)?;
+        self.build_stage(
+            &Stage {
+                label: "instrumented-",
+                build_dir: build_dir.clone(),
+                prefix: build_dir.join("install"),
+                build_type: setting.build_type,
+                target: &setting.target,
+                option: &setting.option,
+                cmake_cache: &setting.cmake_cache,
+                cmake_args: &setting.cmake_args,
+                compiler: HostCompiler::Path(stage1.join("bin").join("clang")),
+                extra: vec![
+                    "-DLLVM_BUILD_INSTRUMENTED=IR".into(),
+                    "-DLLVM_BUILD_RUNTIME=OFF".into(),
+                ],
+                targets: Vec::new(),
+                install: Vec::new(),
+                hooks: None,
+                native: false,
+            },
+            nproc,
+            log,
+        )?;
+
+        let profiles = build_dir.join("profiles");
+        if !log.dry_run {
+            if profiles.exists() {
+                fs::remove_dir_all(&profiles).with(&profiles)?;
+            }
+            fs::create_dir_all(&profiles).with(&profiles)?;
+        }
+        self.train(&build_dir.join("bin"), &profiles, log)?;
+
+        let mut profraws = Vec::new();
+        if !log.dry_run {
+            for entry in fs::read_dir(&profiles).with(&profiles)? {
+                let path = entry.with(&profiles)?.path();
+                if path.extension().is_some_and(|ext| ext == "profraw") {
+                    profraws.push(path);
+                }
+            }
+            if profraws.is_empty() {
+                return Err(Error::ProfileNotGenerated { dir: profiles });
+            }
+        }
+        let profdata = build_dir.join("clang.profdata");
+        log.run(
+            self.command(stage1.join("bin").join("llvm-profdata"))?
+                .arg("merge")
+                .arg(format!("-output={}", profdata.display()))
+                .args(&profraws),
+            "pgo-merge",
+        )?;
+        Ok(profdata)
+    }
+
+    /// Run the training workload by the instrumented clang in `bin`
+    fn train(&self, bin: &Path, profiles: &Path, log: &BuildLog) -> Result<()> {
+        let workdir = TempDir::new().with(env::temp_dir())?;
+        let run = |cmd: &mut process::Command| {
+            log.run(
+                cmd.current_dir(workdir.path())
+                    .env("CC", bin.join("clang"))
+                    .env("CXX", bin.join("clang++"))
+                    .env("LLVM_PROFILE_FILE", profiles.join("%p.profraw")),
+                "pgo-training",
+            )
+        };
+        if let Some(training) = &self.setting().pgo_training {
+            info!("Run PGO training workload: {}", training);
+            return run(self.command("sh")?.args(["-c", training]));
+        }
+        info!("Run bundled PGO training workload");
+        let src = workdir.path().join("pgo-training.cpp");
+        fs::write(&src, PGO_TRAINING_CPP).with(&src)?;
+        for flags in &[["-O0", "-g"], ["-O2", "-DNDEBUG"], ["-O3", "-g"]] {
+            run(self
+                .command(bin.join("clang++"))?
+                .args([
+                    "-std=c++14",
+                    "-c",
+                    "pgo-training.cpp",
+                    "-o",
+                    "pgo-training.o",
+                ])
+                .args(flags))?;
+        }
+        Ok(())
+    }
+
+    fn build_stage(&self, stage: &Stage, nproc: usize, log: &BuildLog) -> Result<()> {
+        let generator = &self.setting().generator;
+        let jobs = self.jobs(stage, nproc)?;
+        info!("Build {}with jobs: {:?}", stage.label, jobs);
+        let hooks = stage.hooks.cloned().unwrap_or_default();
+        self.run_hooks(stage, "pre_configure", &hooks.pre_configure, log)?;
+        self.configure(stage, &jobs, log)?;
+        self.run_hooks(stage, "post_configure", &hooks.post_configure, log)?;
+        let build_option = generator.build_option(jobs.build_nproc(generator), stage.build_type);
+        self.run_hooks(stage, "pre_build", &hooks.pre_build, log)?;
+        if stage.targets.is_empty() {
+            log.run(
+                self.command("cmake")?
+                    .arg("--build")
+                    .arg(&stage.build_dir)
+                    .args(&build_option),
+                &format!("{}build", stage.label),
+            )?;
+        }
+        for target in &stage.targets {
+            log.run(
+                self.command("cmake")?
+                    .arg("--build")
+                    .arg(&stage.build_dir)
+                    .args(["--target", target])
+                    .args(&build_option),
+                &format!("{}build-{}", stage.label, target),
+            )?;
+        }
+        self.run_hooks(stage, "post_build", &hooks.post_build, log)?;
+        if stage.install.is_empty() {
+            return Ok(());
+        }
+        self.run_hooks(stage, "pre_install", &hooks.pre_install, log)?;
+        for target in &stage.install {
+            log.run(
+                self.command("cmake")?
+                    .arg("--build")
+                    .arg(&stage.build_dir)
+                    .args(["--target", target])
+                    .args(&build_option),
+                &format!("{}{}", stage.label, target.replace('/', "-")),
+            )?;
+        }
+        self.run_hooks(stage, "post_install", &hooks.post_install, log)
+    }
+
+    /// Run hook scripts with environment variables describing the stage
+    fn run_hooks(
+        &self,
+        stage: &Stage,
+        name: &str,
+        scripts: &[String],
+        log: &BuildLog,
+    ) -> Result<()> {
+        for script in scripts {
+            info!("Run {} hook: {}", name, script);
+            let mut cmd = self.command("sh")?;
+            cmd.args(["-c", script])
+                .current_dir(&stage.build_dir)
+                .env("LLVMENV_ENTRY", self.name())
+                .env("LLVMENV_SRC_DIR", self.src_dir()?)
+                .env("LLVMENV_BUILD_DIR", &stage.build_dir)
+                .env("LLVMENV_PREFIX", &stage.prefix);
+            log.run(&mut cmd, &format!("{}{}", stage.label, name))?;
+        }
+        Ok(())
+    }
+
+    /// Environment variables of the entry, expanded in shell-like way.
+    /// `PATH_PREPEND` is prepended to `PATH`.
+    pub fn env(&self) -> Result<Vec<(String, String)>> {
+        self.env_with(env::var_os("PATH"), |name| env::var(name).ok())
+    }
+
+    /// Environment variables of the entry, expanded by `var`. `PATH_PREPEND` is prepended to `path`.
+    fn env_with(
+        &self,
+        path: Option<OsString>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<(String, String)>> {
+        let expand = |value: &str| {
+            shellexpand::full_with_context(value, dirs::home_dir, |name| {
+                var(name).map(Some).ok_or(env::VarError::NotPresent)
+            })
+            .map(|value| value.to_string())
+            .map_err(|e| Error::InvalidEntry {
+                name: self.name().into(),
+                message: e.to_string(),
+            })
+        };
+        let mut vars = Vec::new();
+        for (key, value) in &self.setting().env {
+            if key == "PATH_PREPEND" {
+                let mut paths: Vec<PathBuf> = env::split_paths(&expand(value)?).collect();
+                if let Some(path) = &path {
+                    paths.extend(env::split_paths(path));
+                }
+                let path = env::join_paths(paths).map_err(|e| Error::InvalidEntry {
+                    name: self.name().into(),
+                    message: e.to_string(),
+                })?;
+                vars.push(("PATH".to_string(), path.to_string_lossy().into_owned()));
+            } else {
+                vars.push((key.clone(), expand(value)?));
+            }
+        }
+        vars.sort();
+        Ok(vars)
+    }
+
+    /// Command with the environment variables of the entry
+    fn command(&self, program: impl AsRef<OsStr>) -> Result<process::Command> {
+        let mut cmd = process::Command::new(program);
+        cmd.envs(self.env()?);
+        Ok(cmd)
+    }
+
+    /// Command to build the stage, without the environment variables of the entry for the native stage
+    fn stage_command(&self, stage: &Stage, program: impl AsRef<OsStr>) -> Result<process::Command> {
+        if stage.native {
+            Ok(process::Command::new(program))
+        } else {
+            self.command(program)
+        }
+    }
+
+    /// Options for the generator, with the platform, toolset, and extra generator
+    fn generator_option(&self) -> Result<Vec<String>> {
+        let setting = self.setting();
+        let generator = &setting.generator;
+        let extra = setting.extra_generator.as_deref();
+        let invalid = |message: String| Error::InvalidEntry {
+            name: self.name().into(),
+            message,
+        };
+        let mut opts = generator.option();
+        match (extra, generator.name()) {
+            (Some(extra), Some(name)) => opts[1] = format!("{} - {}", extra, name),
+            (Some(extra), None) => {
+                return Err(invalid(format!(
+                    "'extra_generator' ({}) requires 'generator'",
+                    extra
+                )))
+            }
+            _ => {}
+        }
+        if let Some(platform) = &setting.generator_platform {
+            // Old Visual Studio generators have the architecture in their names, e.g. "Visual Studio 15 2017 Win64"
+            if let Some(name) = generator
+                .name()
+                .filter(|name| name.ends_with(" Win64") || name.ends_with(" ARM"))
+            {
+                return Err(invalid(format!(
+                    "'generator_platform' cannot be used with '{}', which specifies the architecture",
+                    name
+                )));
+            }
+            opts.push(format!("-A{}", platform));
+        }
+        if let Some(toolset) = &setting.generator_toolset {
+            // cmake accepts only one -T option, e.g. `-Thost=x64` of VisualStudioWin64
+            match opts.iter_mut().find(|opt| opt.starts_with("-T")) {
+                Some(opt) => *opt = format!("{},{}", opt, toolset),
+                None => opts.push(format!("-T{}", toolset)),
+            }
+        }
+        Ok(opts)
+    }
+
+    fn configure(&self, stage: &Stage, jobs: &Jobs, log: &BuildLog) -> Result<()> {
+        // cmake is not required in dry-run
+        if !log.dry_run {
+            let setting = self.setting();
+            setting
+                .generator
+                .validate(setting.extra_generator.as_deref())?;
+        }
+        log.run(
+            self.stage_command(stage, "cmake")?
+                .args(self.configure_options(stage, jobs)?)
+                .current_dir(&stage.build_dir),
+            &format!("{}configure", stage.label),
+        )
+    }
+
+    /// Arguments of cmake to configure the stage
+    fn configure_options(&self, stage: &Stage, jobs: &Jobs) -> Result<Vec<String>> {
+        let setting = self.setting();
+        let mut opts = self.generator_option()?;
+        opts.push(format!("{}", self.src_dir()?.display()));
+
+        for cache in stage.cmake_cache {
+            opts.push("-C".into());
+            opts.push(format!("{}", self.expand_path(cache)?.display()));
+        }
+
+        opts.push(format!("-DCMAKE_INSTALL_PREFIX={}", stage.prefix.display()));
+        opts.push(format!("-DCMAKE_BUILD_TYPE={:?}", stage.build_type));
+
+        opts.append(&mut self.compiler_launcher()?.resolve().option());
+        opts.append(&mut stage.compiler.option()?);
+        // Stage built by the stage-1 compiler may use lld built with it
+        let stage1 = match &stage.compiler {
+            HostCompiler::Path(clang) => self
+                .stage1_prefix()?
+                .filter(|shared| clang.starts_with(shared))
+                .and_then(|_| clang.parent()?.parent().map(Path::to_path_buf)),
+            _ => None,
+        };
+        opts.append(&mut self.linker()?.resolve(stage1.as_deref()).option());
+        opts.append(&mut jobs.option(&setting.generator));
+
+        // Target architectures
+        if !stage.target.is_empty() {
+            opts.push(format!(
+                "-DLLVM_TARGETS_TO_BUILD={}",
+                stage.target.iter().join(";")
+            ));
+        }
+
+        opts.extend(stage.extra.iter().cloned());
+
+        // Other options
+        for (k, v) in stage.option {
+            opts.push(format!("-D{}={}", k, v));
+        }
+        opts.extend(stage.cmake_args.iter().cloned());
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Release stage of the entry setting without extra options, hooks, and targets
+    fn test_stage<'a>(setting: &'a EntrySetting, build_dir: &Path) -> Stage<'a> {
+        Stage {
+            label: "",
+            build_dir: build_dir.into(),
+            prefix: PathBuf::from("/prefix"),
+            build_type: BuildType::Release,
+            target: &setting.target,
+            option: &setting.option,
+            cmake_cache: &setting.cmake_cache,
+            cmake_args: &setting.cmake_args,
+            compiler: HostCompiler::System,
+            extra: Vec::new(),
+            targets: Vec::new(),
+            install: Vec::new(),
+            hooks: None,
+            native: false,
+        }
+    }
+
+    fn load_entry_toml(toml_str: &str) -> Result<Vec<Entry>> {
+        load_entry_tables(&[toml::from_str(toml_str)?])
+    }
+
+    #[test]
+    fn parse_url() {
+        let setting = EntrySetting {
+            url: Some("http://llvm.org/svn/llvm-project/llvm/trunk".into()),
+            ..Default::default()
+        };
+        let _entry = Entry::parse_setting("url", None, setting).unwrap();
+    }
+
+    #[test]
+    fn parse_path() {
+        let setting = EntrySetting {
+            path: Some("~/.config/llvmenv".into()),
+            ..Default::default()
+        };
+        let _entry = Entry::parse_setting("path", None, setting).unwrap();
+    }
+
+    #[should_panic]
+    #[test]
+    fn parse_no_entry() {
+        let setting = EntrySetting::default();
+        let _entry = Entry::parse_setting("no_entry", None, setting).unwrap();
+    }
+
+    #[should_panic]
+    #[test]
+    fn parse_duplicated() {
+        let setting = EntrySetting {
+            url: Some("http://llvm.org/svn/llvm-project/llvm/trunk".into()),
+            path: Some("~/.config/llvmenv".into()),
+            ..Default::default()
+        };
+        let _entry = Entry::parse_setting("duplicated", None, setting).unwrap();
+    }
+
+    #[test]
+    fn parse_with_version() {
+        let path = "~/.config/llvmenv";
+        let version = Version::new(10, 0, 0);
+        let setting = EntrySetting {
+            path: Some(path.into()),
+            ..Default::default()
+        };
+        let entry = Entry::parse_setting("path", Some(version.clone()), setting.clone()).unwrap();
+
+        assert_eq!(entry.version(), Some(&version));
+        assert_eq!(
+            entry,
+            Entry::Local {
+                name: "path".into(),
+                version: Some(version),
+                path: PathBuf::from(shellexpand::full(path).unwrap().to_string()),
+                setting,
+            }
+        )
+    }
+
+    #[test]
+    fn unknown_tool() {
+        let tool = Tool::new("my-tool", "https://github.com/llvm-mirror/clang");
+        assert!(matches!(
+            tool.rel_path().unwrap_err(),
+            Error::UnknownTool { .. }
+        ));
+        let tool = Tool {
+            relative_path: Some("tools/my-tool".into()),
+            ..tool
+        };
+        assert_eq!(tool.rel_path().unwrap(), "tools/my-tool");
+    }
+
+    #[test]
+    fn official_releases_are_valid() {
+        assert!(official_releases().is_ok());
+    }
+
+    #[test]
+    fn inherits_entry() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [base]
+            url = "https://github.com/llvm/llvm-project"
+            target = ["X86"]
+            option = { LLVM_ENABLE_ASSERTIONS = "ON", LLVM_USE_SANITIZER = "Address" }
+
+            [[base.tools]]
+            name = "clang"
+            url = "https://github.com/llvm-mirror/clang"
+
+            [child]
+            inherits = "base"
+            build_type = "Debug"
+            option = { LLVM_USE_SANITIZER = "Memory" }
+
+            [[child.tools]]
+            name = "clang"
+            relative_path = "tools/my-clang"
+
+            [[child.tools]]
+            name = "lld"
+            url = "https://github.com/llvm-mirror/lld"
+            "#,
+        )?;
+        let child = entries.iter().find(|e| e.name() == "child").unwrap();
+        let setting = child.setting();
+        assert_eq!(
+            setting.url.as_deref(),
+            Some("https://github.com/llvm/llvm-project")
+        );
+        assert_eq!(setting.target, vec!["X86".to_string()]);
+        assert_eq!(setting.build_type, BuildType::Debug);
+        assert_eq!(setting.option["LLVM_ENABLE_ASSERTIONS"], "ON");
+        assert_eq!(setting.option["LLVM_USE_SANITIZER"], "Memory");
+        assert_eq!(setting.tools.len(), 2);
+        assert_eq!(setting.tools[0].url, "https://github.com/llvm-mirror/clang");
+        assert_eq!(setting.tools[0].rel_path()?, "tools/my-clang");
+        assert_eq!(setting.tools[1].name, "lld");
+        Ok(())
+    }
+
+    #[test]
+    fn inherits_official() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [local]
+            inherits = "13.0.0"
+            path = "/path/to/llvm"
+            generator = "Ninja"
+            "#,
+        )?;
+        match &entries[0] {
+            Entry::Local { setting, .. } => {
+                assert_eq!(setting.url, None);
+                assert_eq!(setting.generator, CMakeGenerator::Ninja);
+                assert!(setting.tools.is_empty());
+            }
+            _ => panic!("Must be a local entry"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn inherits_cyclic() {
+        let err = load_entry_toml(
+            r#"
+            [a]
+            inherits = "b"
+            [b]
+            inherits = "a"
+            "#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidEntry { .. }));
+        assert!(load_entry_toml("[a]\ninherits = \"nonexistent\"").is_err());
+    }
+
+    #[test]
+    fn inherits_official_of_same_name() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            ["13.0.0"]
+            inherits = "13.0.0"
+            target = ["X86"]
+            "#,
+        )?;
+        let official = Entry::official(13, 0, 0)?;
+        let setting = entries[0].setting();
+        assert_eq!(entries[0].version(), official.version());
+        assert_eq!(setting.url, official.setting().url);
+        assert_eq!(setting.tools, official.setting().tools);
+        assert_eq!(setting.target, vec!["X86".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn project_shadows_global() -> Result<()> {
+        let project: toml::value::Table = toml::from_str(
+            r#"
+            [my-llvm]
+            inherits = "my-llvm"
+            build_type = "Debug"
+            "#,
+        )?;
+        let global: toml::value::Table = toml::from_str(
+            r#"
+            [my-llvm]
+            path = "/path/to/llvm"
+            target = ["X86"]
+
+            [other]
+            inherits = "my-llvm"
+            "#,
+        )?;
+        let entries = load_entry_tables(&[project, global])?;
+        assert_eq!(entries.len(), 2);
+        let setting = entries[0].setting();
+        assert_eq!(entries[0].name(), "my-llvm");
+        assert_eq!(setting.build_type, BuildType::Debug);
+        assert_eq!(setting.target, vec!["X86".to_string()]);
+        // entries in global setting inherit global ones
+        assert_eq!(entries[1].setting().build_type, BuildType::Release);
+        Ok(())
+    }
+
+    #[test]
+    fn relative_paths() -> Result<()> {
+        let mut project: toml::value::Table = toml::from_str(
+            r#"
+            [here]
+            path = "llvm"
+            build_dir = "../build"
+
+            [home]
+            path = "~/llvm"
+            build_dir = "/tmp/build"
+
+            [remote]
+            url = "https://github.com/llvm/llvm-project"
+            patches = ["patches/fix.patch", "https://example.com/D12345.diff"]
+            tools = [{ name = "clang", url = "https://example.com/clang.tar.xz", patches = ["clang.patch"] }]
+            "#,
+        )?;
+        resolve_relative_paths(&mut project, Path::new("/path/to/project"));
+        let entries = load_entry_tables(&[project])?;
+        for entry in &entries {
+            match entry {
+                Entry::Local { name, path, .. } if name == "here" => {
+                    assert_eq!(path, Path::new("/path/to/project/llvm"));
+                    assert_eq!(
+                        entry.build_dir_path()?,
+                        Path::new("/path/to/project/../build")
+                    );
+                }
+                Entry::Local { path, .. } => {
+                    assert_eq!(
+                        path,
+                        &PathBuf::from(shellexpand::tilde("~/llvm").to_string())
+                    );
+                    assert_eq!(entry.build_dir_path()?, Path::new("/tmp/build"));
+                }
+                Entry::Remote { tools, .. } => {
+                    assert_eq!(
+                        entry.setting().patches,
+                        vec![
+                            "/path/to/project/patches/fix.patch",
+                            "https://example.com/D12345.diff"
+                        ]
+                    );
+                    assert_eq!(tools[0].patches, vec!["/path/to/project/clang.patch"]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bootstrap() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [default-stage1]
+            path = "/path/to/llvm"
+            bootstrap = {}
+
+            [shared-stage1]
+            path = "/path/to/llvm"
+            bootstrap = { name = "shared", build_type = "Debug" }
+
+            [no-bootstrap]
+            path = "/path/to/llvm"
+            "#,
+        )?;
+        for entry in &entries {
+            let prefix = entry.stage1_prefix()?;
+            match entry.name() {
+                "default-stage1" => {
+                    assert!(prefix.unwrap().ends_with("stage1/default-stage1-stage1"))
+                }
+                "shared-stage1" => {
+                    assert!(prefix.unwrap().ends_with("stage1/shared"));
+                    assert_eq!(
+                        entry.setting().bootstrap.as_ref().unwrap().build_type,
+                        BuildType::Debug
+                    );
+                }
+                _ => assert!(prefix.is_none()),
+            }
+        }
+        // Stage-1 compilers of different sources are installed separately
+        let key = source_key(Some(&["a".into()]));
+        assert_eq!(key.len(), 12);
+        assert_ne!(key, source_key(Some(&["b".into()])));
+        assert_eq!(source_key(None), "unversioned");
+        Ok(())
+    }
+
+    #[test]
+    fn stage1_projects() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [monorepo]
+            path = "/path/to/llvm-project/llvm"
+            target = ["X86"]
+            option = { LLVM_ENABLE_PROJECTS = "clang;clang-tools-extra" }
+            bootstrap = {}
+
+            [explicit]
+            path = "/path/to/llvm-project/llvm"
+            bootstrap = { target = ["AArch64"], option = { LLVM_ENABLE_PROJECTS = "lld" } }
+
+            [tools]
+            path = "/path/to/llvm"
+            bootstrap = {}
+
+            [pgo]
+            path = "/path/to/llvm-project/llvm"
+            option = { LLVM_ENABLE_PROJECTS = "clang" }
+            optimize = "pgo"
+            "#,
+        )?;
+        for entry in &entries {
+            let stage1 = entry.stage1_setting(entry.bootstrap().unwrap());
+            let projects = stage1
+                .option
+                .get("LLVM_ENABLE_PROJECTS")
+                .map(String::as_str);
+            match entry.name() {
+                "monorepo" => {
+                    assert_eq!(projects, Some("clang"));
+                    assert_eq!(stage1.target, vec!["X86"]);
+                }
+                "explicit" => {
+                    assert_eq!(projects, Some("lld;clang"));
+                    assert_eq!(stage1.target, vec!["AArch64"]);
+                }
+                "tools" => assert_eq!(projects, None),
+                "pgo" => assert_eq!(projects, Some("clang;lld;compiler-rt")),
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_optimize() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [pgo]
+            path = "/path/to/llvm"
+            optimize = "pgo+thinlto"
+
+            [none]
+            path = "/path/to/llvm"
+            "#,
+        )?;
+        for entry in &entries {
+            let optimize = entry.setting().optimize;
+            match entry.name() {
+                "pgo" => {
+                    assert!(optimize.pgo() && optimize.thinlto());
+                    // PGO requires stage-1 compiler
+                    assert!(entry.stage1_prefix()?.is_some());
+                }
+                _ => {
+                    assert_eq!(optimize, Optimize::None);
+                    assert!(entry.stage1_prefix()?.is_none());
+                }
+            }
+        }
+        assert!(load_entry_toml("[a]\npath = \"/path\"\noptimize = \"O3\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_launcher_linker() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [sccache]
+            path = "/path/to/llvm"
+            compiler_launcher = "sccache"
+            linker = "mold"
+
+            [derived]
+            inherits = "sccache"
+            linker = "system"
+            "#,
+        )?;
+        let entry = |name: &str| entries.iter().find(|e| e.name() == name).unwrap();
+        let sccache = entry("sccache").setting();
+        assert_eq!(sccache.compiler_launcher, Some(CompilerLauncher::Sccache));
+        assert_eq!(
+            CompilerLauncher::Sccache.resolve().option(),
+            vec![
+                "-DCMAKE_C_COMPILER_LAUNCHER=sccache",
+                "-DCMAKE_CXX_COMPILER_LAUNCHER=sccache"
+            ]
+        );
+        assert_eq!(
+            sccache.linker.unwrap().option(),
+            vec!["-DLLVM_USE_LINKER=mold"]
+        );
+        let derived = entry("derived").setting();
+        assert_eq!(derived.compiler_launcher, Some(CompilerLauncher::Sccache));
+        assert_eq!(derived.linker, Some(Linker::System));
+        assert!(Linker::System.option().is_empty());
+
+        // lld in PATH is not used unless it is built in stage 1
+        let stage1 = TempDir::new()?;
+        assert_eq!(Linker::Auto.resolve(None), Linker::System);
+        assert_eq!(Linker::Auto.resolve(Some(stage1.path())), Linker::System);
+        assert_eq!(Linker::Lld.resolve(None), Linker::Lld);
+        fs::create_dir(stage1.path().join("bin"))?;
+        fs::write(stage1.path().join("bin").join("ld.lld"), "")?;
+        assert_eq!(Linker::Auto.resolve(Some(stage1.path())), Linker::Lld);
+
+        assert!(load_entry_toml("[a]\npath = \"/path\"\nlinker = \"bfd\"").is_err());
+        assert!(Linker::from_str("bfd").is_err());
+        assert_eq!(CompilerLauncher::from_str("None")?, CompilerLauncher::None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_generator() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [vs2022]
+            path = "/path/to/llvm"
+            generator = "Visual Studio 17 2022"
+            generator_platform = "x64"
+            generator_toolset = "ClangCL"
+
+            [derived]
+            inherits = "vs2022"
+            "#,
+        )?;
+        for entry in &entries {
+            let setting = entry.setting();
+            let generator = &setting.generator;
+            assert_eq!(
+                *generator,
+                CMakeGenerator::Other("Visual Studio 17 2022".into())
+            );
+            assert!(generator.is_multi_config());
+            assert_eq!(setting.generator_platform.as_deref(), Some("x64"));
+            assert_eq!(
+                generator.build_option(8, BuildType::Release),
+                vec!["--config", "Release", "--parallel", "8"]
+            );
+        }
+        // Round trip of old names
+        for generator in &[
+            CMakeGenerator::Platform,
+            CMakeGenerator::Makefile,
+            CMakeGenerator::NinjaMultiConfig,
+            CMakeGenerator::VisualStudioWin64,
+        ] {
+            assert_eq!(
+                CMakeGenerator::from_str(&String::from(generator.clone()))?,
+                *generator
+            );
+        }
+        assert!(CMakeGenerator::NinjaMultiConfig.is_ninja());
+        let build_dir = Path::new("/build");
+        assert_eq!(
+            CMakeGenerator::NinjaMultiConfig.bin_dir(build_dir, BuildType::Release),
+            Path::new("/build/Release/bin")
+        );
+        assert_eq!(
+            CMakeGenerator::Ninja.bin_dir(build_dir, BuildType::Release),
+            Path::new("/build/bin")
+        );
+        assert_eq!(
+            CMakeGenerator::NinjaMultiConfig.build_option(4, BuildType::Debug),
+            vec!["--config", "Debug", "--parallel", "4"]
+        );
+        assert_eq!(
+            CMakeGenerator::Other("Unix Makefiles".into()).build_option(4, BuildType::Debug),
+            vec!["--parallel", "4"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generator_options() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [vs2022]
+            path = "/path/to/llvm"
+            generator = "Visual Studio 17 2022"
+            generator_platform = "x64"
+            generator_toolset = "ClangCL"
+
+            [win64]
+            path = "/path/to/llvm"
+            generator = "VisualStudioWin64"
+            generator_toolset = "v141"
+
+            [win64-platform]
+            path = "/path/to/llvm"
+            generator = "VisualStudioWin64"
+            generator_platform = "x64"
+
+            [codeblocks]
+            path = "/path/to/llvm"
+            generator = "Ninja"
+            extra_generator = "CodeBlocks"
+
+            [extra-only]
+            path = "/path/to/llvm"
+            extra_generator = "CodeBlocks"
+            "#,
+        )?;
+        for entry in &entries {
+            let opts = entry.generator_option();
+            match entry.name() {
+                "vs2022" => assert_eq!(
+                    opts?,
+                    vec!["-G", "Visual Studio 17 2022", "-Ax64", "-TClangCL"]
+                ),
+                "win64" => assert_eq!(
+                    opts?,
+                    vec!["-G", "Visual Studio 15 2017 Win64", "-Thost=x64,v141"]
+                ),
+                "codeblocks" => assert_eq!(opts?, vec!["-G", "CodeBlocks - Ninja"]),
+                _ => assert!(matches!(opts, Err(Error::InvalidEntry { .. }))),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cmake_help_generators() {
+        let help = r#"
+Usage
+
+  cmake [options] <path-to-source>
+
+Options
+  -G <generator-name>          = Specify a build system generator.
+
+Generators
+
+The following generators are available on this platform (* marks default):
+* Unix Makefiles               = Generates standard UNIX makefiles.
+  Ninja                        = Generates build.ninja files.
+  Ninja Multi-Config           = Generates build-<Config>.ninja files.
+  Visual Studio 15 2017 [arch] = Generates Visual Studio 2017 project files.
+                                 Optional [arch] can be "Win64" or "ARM".
+  CodeBlocks - Ninja           = Generates CodeBlocks project files.
+  Sublime Text 2 - Unix Makefiles
+                               = Generates Sublime Text 2 project files.
+"#;
+        assert_eq!(
+            parse_generators(help),
+            vec![
+                "Unix Makefiles",
+                "Ninja",
+                "Ninja Multi-Config",
+                "Visual Studio 15 2017 [arch]",
+                "CodeBlocks - Ninja",
+                "Sublime Text 2 - Unix Makefiles",
+            ]
+        );
+    }
+
+    #[test]
+    fn cmake_cache_and_args() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [dist]
+            path = "/path/to/llvm"
+            cmake_cache = ["clang/cmake/caches/Release.cmake", "/etc/llvm.cmake"]
+            cmake_args = ["--log-level=VERBOSE", "-Wno-dev"]
+            "#,
+        )?;
+        let entry = &entries[0];
+        let setting = entry.setting();
+        let stage = Stage {
+            install: vec!["install".into()],
+            ..test_stage(setting, Path::new("/path/to/llvm/build"))
+        };
+        let opts =
+            entry.configure_options(&stage, &Jobs::new(1, None, BuildType::Release, false))?;
+        assert_eq!(opts[0], "/path/to/llvm");
+        assert_eq!(
+            &opts[1..5],
+            &[
+                "-C",
+                "/path/to/llvm/clang/cmake/caches/Release.cmake",
+                "-C",
+                "/etc/llvm.cmake"
+            ]
+        );
+        assert_eq!(
+            &opts[opts.len() - 2..],
+            &["--log-level=VERBOSE", "-Wno-dev"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_install_targets() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [all]
+            path = "/path/to/llvm"
+
+            [dist]
+            path = "/path/to/llvm"
+            distribution_components = ["clang", "lld", "clang-format", "clangd"]
+            strip = true
+
+            [tools]
+            path = "/path/to/llvm"
+            install_targets = ["clang", "lld"]
+            "#,
+        )?;
+        for entry in &entries {
+            let (targets, install) = entry.install_targets();
+            match entry.name() {
+                "all" => {
+                    assert!(targets.is_empty());
+                    assert_eq!(install, vec!["install"]);
+                }
+                "dist" => {
+                    assert_eq!(targets, vec!["distribution"]);
+                    assert_eq!(install, vec!["install-distribution-stripped"]);
+                }
+                "tools" => {
+                    assert_eq!(targets, vec!["clang", "lld"]);
+                    assert_eq!(install, vec!["install-clang", "install-lld"]);
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_patches_once() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let src = tmp.path().join("llvm");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("hello.txt"), "hello\n")?;
+        let patch = tmp.path().join("fix.patch");
+        fs::write(
+            &patch,
+            "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+hello, patched\n",
+        )?;
+        let entries = load_entry_toml(&format!(
+            "[patched]\npath = {:?}\npatches = [{:?}]",
+            src.display().to_string(),
+            patch.display().to_string()
+        ))?;
+        let entry = &entries[0];
+        entry.checkout()?;
+        // Applied only once
+        entry.checkout()?;
+        assert_eq!(
+            fs::read_to_string(src.join("hello.txt"))?,
+            "hello, patched\n"
+        );
+        let applied = entry.applied_patches()?;
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].dir, "");
+        assert_eq!(applied[0].sha256.len(), 64);
+
+        // Patch of URL already applied is not downloaded again
+        let url = "http://127.0.0.1:9/fix.patch";
+        let remote = &load_entry_toml(&format!(
+            "[remote]\npath = {:?}\npatches = [{:?}]",
+            src.display().to_string(),
+            url
+        ))?[0];
+        remote.write_patch_stamp(&[AppliedPatch {
+            patch: url.into(),
+            dir: String::new(),
+            sha256: applied[0].sha256.clone(),
+        }])?;
+        remote.apply_patches()?;
+
+        // Patch which does not apply fails without modifying the source
+        let content = fs::read(&patch)?;
+        match apply_patch("fix.patch", &content, &src, 1) {
+            Err(Error::PatchFailed { patch, .. }) => assert_eq!(patch, "fix.patch"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            fs::read_to_string(src.join("hello.txt"))?,
+            "hello, patched\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn run_hooks() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let entries = load_entry_toml(&format!(
+            r#"
+            [hooked]
+            path = {:?}
+            hooks.post_install = ["echo $LLVMENV_ENTRY:$LLVMENV_PREFIX > hook.txt", "exit 3"]
+            "#,
+            tmp.path().display().to_string()
+        ))?;
+        let entry = &entries[0];
+        let setting = entry.setting();
+        let stage = Stage {
+            hooks: Some(&setting.hooks),
+            ..test_stage(setting, tmp.path())
+        };
+        let log_dir = tmp.path().join("log");
+        let log = BuildLog::new(&log_dir, false)?;
+        match entry.run_hooks(&stage, "post_install", &setting.hooks.post_install, &log) {
+            Err(Error::LoggedCommandError { errno, log, .. }) => {
+                assert_eq!(errno, Some(3));
+                assert!(log.ends_with("02-post_install.log"));
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("hook.txt"))?,
+            "hooked:/prefix\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn entry_env() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let entries = load_entry_toml(&format!(
+            r#"
+            [legacy]
+            path = {:?}
+            env = {{ CXXFLAGS = "-include cstdint $LLVMENV_TEST_FLAGS", PATH_PREPEND = "/opt/bin" }}
+            "#,
+            tmp.path().display().to_string()
+        ))?;
+        let entry = &entries[0];
+        let var = |name: &str| Some("-O1".to_string()).filter(|_| name == "LLVMENV_TEST_FLAGS");
+        let vars = entry.env_with(Some("/usr/bin".into()), var)?;
+        assert_eq!(
+            vars,
+            vec![
+                ("CXXFLAGS".into(), "-include cstdint -O1".into()),
+                ("PATH".into(), "/opt/bin:/usr/bin".into())
+            ]
+        );
+        // Undefined variable is an error
+        assert!(entry.env_with(None, |_| None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_log_dirs() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let first = BuildLog::new(tmp.path(), false)?;
+        let second = BuildLog::new(tmp.path(), false)?;
+        assert_ne!(first.dir, second.dir);
+        assert!(first.dir.is_dir() && second.dir.is_dir());
+        assert_eq!(first.step("configure"), first.dir.join("01-configure.log"));
+        assert_eq!(first.step("build"), first.dir.join("02-build.log"));
+        Ok(())
+    }
+
+    #[test]
+    fn display_commands() {
+        let mut cmd = process::Command::new("cmake");
+        cmd.args(["-G", "Unix Makefiles", "-DFOO=bar"])
+            .current_dir("/path/to/build")
+            .env("CFLAGS", "-O2 -g");
+        assert_eq!(
+            display_command(&cmd),
+            "cd /path/to/build && CFLAGS='-O2 -g' cmake -G 'Unix Makefiles' -DFOO=bar"
+        );
+        assert_eq!(
+            shell_quote("/Users/John Doe/it's.json"),
+            "'/Users/John Doe/it'\\''s.json'"
+        );
+        assert_eq!(
+            shell_quote("C:\\llvmenv\\check.json"),
+            "'C:\\llvmenv\\check.json'"
+        );
+    }
+
+    #[test]
+    fn install_name() -> Result<()> {
+        let mut entries = load_entry_toml(
+            r#"
+            [debug]
+            path = "/path/to/llvm"
+            build_type = "Debug"
+            install_name = "{name}-{build_type}"
+
+            [plain]
+            path = "/path/to/llvm"
+
+            [cross]
+            path = "/path/to/llvm"
+            host_triple = "aarch64-linux-gnu"
+            "#,
+        )?;
+        for entry in &mut entries {
+            match entry.name() {
+                "debug" => {
+                    assert_eq!(entry.install_name()?, "debug-Debug");
+                    assert!(entry.prefix()?.ends_with("debug-Debug"));
+                }
+                "plain" => {
+                    assert_eq!(entry.install_name()?, "plain");
+                    entry.set_install_suffix("asan")?;
+                    assert_eq!(entry.install_name()?, "plain-asan");
+                    entry.set_install_name("my-llvm")?;
+                    assert_eq!(entry.install_name()?, "my-llvm");
+                    assert!(entry.set_install_name("{unknown}").is_err());
+                }
+                "cross" => {
+                    assert_eq!(entry.install_name()?, "cross-aarch64-linux-gnu");
+                    entry.set_install_suffix("{build_type}")?;
+                    assert_eq!(entry.install_name()?, "cross-aarch64-linux-gnu-Release");
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn build_dir() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let entries = load_entry_toml(&format!(
+            r#"
+            [scratch]
+            path = "/path/to/llvm"
+            build_dir = {:?}
+
+            [default]
+            path = "/path/to/llvm"
+            install_name = "{{name}}-{{build_type}}"
+            "#,
+            tmp.path().join("scratch").display().to_string()
+        ))?;
+        for entry in &entries {
+            match entry.name() {
+                "scratch" => assert_eq!(entry.build_dir()?, tmp.path().join("scratch")),
+                "default" => assert_eq!(
+                    entry.default_build_dir(&build_root_from(Some(
+                        tmp.path().display().to_string()
+                    ))?)?,
+                    tmp.path().join("default-Release")
+                ),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(
+            build_root_from(Some("~/builds".into()))?,
+            dirs::home_dir().unwrap().join("builds")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pin_revision() -> Result<()> {
+        let entries = load_entry_toml(
+            r#"
+            [pinned]
+            url = "https://github.com/llvm/llvm-project#main"
+            rev = "d7b669b3a30345cfcdb2fde2af6f48aa4b94845d"
+
+            [short]
+            url = "https://github.com/llvm/llvm-project"
+            rev = "d7b669b3"
+
+            [tagged]
+            inherits = "pinned"
+            tag = "llvmorg-13.0.0"
+
+            [both]
+            url = "https://github.com/llvm/llvm-project"
+            rev = "d7b669b3a30345cfcdb2fde2af6f48aa4b94845d"
+            tag = "llvmorg-13.0.0"
+
+            [tar]
+            url = "http://releases.llvm.org/6.0.1/llvm-6.0.1.src.tar.xz"
+            tag = "llvmorg-6.0.1"
+            "#,
+        )?;
+        let entry = |name: &str| entries.iter().find(|e| e.name() == name).unwrap();
+        let rev = |name: &str| match entry(name).resources().unwrap().remove(0).1 {
+            Resource::Git { branch, rev, .. } => {
+                assert_eq!(branch, None);
+                rev
+            }
+            r => panic!("Unexpected resource: {:?}", r),
+        };
+        assert_eq!(
+            rev("pinned").as_deref(),
+            Some("d7b669b3a30345cfcdb2fde2af6f48aa4b94845d")
+        );
+        assert!(matches!(
+            entry("short").resources(),
+            Err(Error::InvalidRevision { .. })
+        ));
+        assert_eq!(rev("tagged").as_deref(), Some("refs/tags/llvmorg-13.0.0"));
+        assert!(entry("both").resources().is_err());
+        assert!(entry("tar").resources().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_without_build() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let build_dir = tmp.path().join("build");
+        let entries = load_entry_toml(&format!(
+            "[llvmenv-test-not-built]\npath = {:?}\nbuild_dir = {:?}",
+            tmp.path().display().to_string(),
+            build_dir.display().to_string()
+        ))?;
+        assert!(matches!(
+            entries[0].test(&["check-llvm".into()], 1),
+            Err(Error::BuildNotFound { .. })
+        ));
+        // Not left as an empty build directory counted by `llvmenv du`
+        assert!(!build_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn unshallow_local() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let repo = tmp.path().join("repo");
+        fs::create_dir(&repo)?;
+        test_git(&repo, &["init", "-q"])?;
+        for message in ["first", "second"] {
+            fs::write(repo.join("README"), message)?;
+            test_git(&repo, &["add", "."])?;
+            test_git(&repo, &["commit", "-q", "-m", message])?;
+        }
+        let src = tmp.path().join("llvm");
+        test_git(
+            tmp.path(),
+            &[
+                "clone",
+                "-q",
+                "--depth",
+                "1",
+                &format!("file://{}", repo.display()),
+                "llvm",
+            ],
+        )?;
+        let entries = load_entry_toml(&format!("[local]\npath = {:?}", src.display().to_string()))?;
+        // History of the local source is not fetched by llvmenv
+        assert!(matches!(
+            entries[0].unshallow(),
+            Err(Error::InvalidEntry { .. })
+        ));
+        assert!(is_shallow(&src)?);
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let src = tmp.path();
+        let git = |args: &[&str]| test_git(src, args);
+        git(&["init", "-q"])?;
+        fs::write(src.join("CMakeLists.txt"), "project(llvm)\n")?;
+        git(&["add", "."])?;
+        git(&["commit", "-q", "-m", "init"])?;
+        let revision = source_revision(src, true).unwrap();
+        assert_eq!(revision.len(), 40);
+
+        let mut entries =
+            load_entry_toml(&format!("[local]\npath = {:?}", src.display().to_string()))?;
+        let entry = &mut entries[0];
+        let fingerprint = entry.fingerprint()?.unwrap();
+        assert_eq!(fingerprint.source, vec![revision.clone()]);
+        assert_eq!(entry.fingerprint()?.as_ref(), Some(&fingerprint));
+
+        entry.set_build_type(BuildType::Debug)?;
+        let debug = entry.fingerprint()?.unwrap();
+        assert_ne!(debug, fingerprint);
+        assert_eq!(debug.build_type, BuildType::Debug);
+
+        // Global defaults and environment variables are resolved
+        entry.set_linker(Linker::Mold)?;
+        entry
+            .setting_mut()
+            .env
+            .insert("LLVMENV_TEST_PREFIX".into(), "~/prefix".into());
+        let resolved = entry.fingerprint()?.unwrap();
+        assert_eq!(resolved.linker, Linker::Mold);
+        assert_eq!(
+            resolved.env,
+            vec![(
+                "LLVMENV_TEST_PREFIX".into(),
+                shellexpand::tilde("~/prefix").into()
+            )]
+        );
+        // Round trip of toml
+        let store = TempDir::new()?;
+        let stored = store.path().join(FINGERPRINT_FN);
+        resolved.write(&stored)?;
+        assert_eq!(Fingerprint::read(&stored)?, Some(resolved));
+
+        // Uncommitted changes of local source cannot be fingerprinted
+        fs::write(src.join("CMakeLists.txt"), "project(llvm2)\n")?;
+        assert!(entry.fingerprint()?.is_none());
+        assert_eq!(source_revision(src, false), Some(revision));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_size() -> Result<()> {
+        assert_eq!(parse_memory_size("64G")?, 64 << 30);
+        assert_eq!(parse_memory_size("512MiB")?, 512 << 20);
+        assert_eq!(parse_memory_size("2 tb")?, 2 << 40);
+        assert_eq!(parse_memory_size("1024")?, 1024);
+        assert!(parse_memory_size("64X").is_err());
+        assert!(parse_memory_size("G").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn memory_aware_jobs() {
+        let gib = |n: u64| Some(n << 30);
+        let jobs = Jobs::new(64, gib(128), BuildType::Release, false);
+        assert_eq!(jobs.compile, Some(64));
+        // Limited by the cap of link jobs rather than memory
+        assert_eq!(jobs.link, 16);
+        assert_eq!(
+            jobs.option(&CMakeGenerator::Ninja),
+            vec!["-DLLVM_PARALLEL_LINK_JOBS=16"]
+        );
+
+        let jobs = Jobs::new(64, gib(128), BuildType::Debug, false);
+        assert_eq!(jobs.link, 8);
+        let jobs = Jobs::new(64, gib(128), BuildType::Release, true);
+        assert_eq!(jobs.link, 8);
+
+        let jobs = Jobs::new(64, gib(32), BuildType::RelWithDebInfo, false);
+        assert_eq!(
+            jobs.option(&CMakeGenerator::Ninja),
+            vec![
+                "-DLLVM_PARALLEL_COMPILE_JOBS=21",
+                "-DLLVM_PARALLEL_LINK_JOBS=4"
+            ]
+        );
+        assert_eq!(jobs.build_nproc(&CMakeGenerator::Ninja), 64);
+        assert!(jobs.option(&CMakeGenerator::Makefile).is_empty());
+        assert_eq!(jobs.build_nproc(&CMakeGenerator::Makefile), 21);
+
+        // At least one job
+        assert_eq!(Jobs::new(8, gib(1), BuildType::Debug, true).link, 1);
+        assert_eq!(Jobs::new(2, gib(128), BuildType::Release, false).link, 1);
+        // Memory is unknown
+        let jobs = Jobs::new(8, None, BuildType::Debug, false);
+        assert_eq!(
+            jobs.option(&CMakeGenerator::Ninja),
+            vec!["-DLLVM_PARALLEL_LINK_JOBS=2"]
+        );
+        assert_eq!(jobs.build_nproc(&CMakeGenerator::Makefile), 8);
+
+        assert_eq!(
+            CMakeGenerator::Platform.build_option(8, BuildType::Release),
+            vec!["--parallel", "8"]
+        );
+    }
+
+    #[test]
+    fn host_compiler_option() -> Result<()> {
+        let gcc = HostCompiler::from_str("/usr/bin/gcc-7")?;
+        assert_eq!(
+            gcc.option()?,
+            vec![
+                "-DCMAKE_C_COMPILER=/usr/bin/gcc-7",
+                "-DCMAKE_CXX_COMPILER=/usr/bin/g++-7"
+            ]
+        );
+        assert!(HostCompiler::from_str("/usr/bin/icc")?.option().is_err());
+        assert_eq!(
+            HostCompiler::from_str("/usr/bin/aarch64-linux-gnu-gcc")?.option()?[1],
+            "-DCMAKE_CXX_COMPILER=/usr/bin/aarch64-linux-gnu-g++"
+        );
+        assert!(HostCompiler::System.option()?.is_empty());
+
+        // inherited through TOML value
+        let entries = load_entry_toml(
+            r#"
+            [base]
+            path = "/path/to/llvm"
+            host_compiler = "build:12.0.1"
+            [child]
+            inherits = "base"
+            "#,
+        )?;
+        for entry in &entries {
+            assert_eq!(
+                entry.setting().host_compiler,
+                HostCompiler::Build("12.0.1".into())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn cross_platform() {
+        assert!(same_platform("x86_64-linux-gnu", "x86_64-pc-linux-gnu"));
+        assert!(same_platform(
+            "arm64-apple-darwin",
+            "aarch64-apple-darwin20.1.0"
+        ));
+        assert!(!same_platform("x86_64-linux-gnu", "aarch64-linux-gnu"));
+        assert!(!same_platform("aarch64-linux-gnu", "aarch64-linux-android"));
+        let file = toolchain_file("riscv64-unknown-linux-gnu");
+        assert!(file.contains("set(CMAKE_SYSTEM_NAME Linux)"));
+        assert!(file.contains("set(CMAKE_SYSTEM_PROCESSOR riscv64)"));
+        assert!(file.contains("set(CMAKE_CXX_COMPILER_TARGET riscv64-unknown-linux-gnu)"));
+    }
+
+    #[test]
+    fn cross_compiler() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let log = BuildLog::new(tmp.path(), true)?;
+        let entry = Entry::parse_setting(
+            "riscv",
+            None,
+            EntrySetting {
+                path: Some(tmp.path().display().to_string()),
+                host_triple: Some("riscv64-unknown-linux-gnu".into()),
+                ..Default::default()
+            },
+        )?;
+        // Generated toolchain file cannot be used by gcc
+        assert!(matches!(
+            entry.cross_options(&HostCompiler::Path("/usr/bin/gcc".into()), 1, &log),
+            Err(Error::InvalidEntry { .. })
+        ));
+        assert!(matches!(
+            entry.cross_options(&HostCompiler::System, 1, &log),
+            Err(Error::InvalidEntry { .. })
+        ));
+        Ok(())
     }
 
     macro_rules! checkout {
@@ -684,7 +4383,7 @@ mod tests {
                 #[ignore]
                 #[test]
                 fn [< checkout_ $major _ $minor _ $patch >]() {
-                    Entry::official($major, $minor, $patch).checkout().unwrap();
+                    Entry::official($major, $minor, $patch).unwrap().checkout().unwrap();
                 }
             }
         };
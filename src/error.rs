@@ -1,11 +1,23 @@
-use std::{io, path::*, process};
+use itertools::Itertools;
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::*,
+    process,
+    sync::{Arc, Mutex},
+    thread,
+};
 use thiserror::Error;
 
+/// Number of output lines kept in [Error::LoggedCommandError]
+pub const LOG_TAIL_LINES: usize = 40;
+
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("IO error while accessing {path}: {source:?}")]
+    #[error("IO error while accessing {path}")]
     FileIo { path: PathBuf, source: io::Error },
 
     #[error(transparent)]
@@ -23,24 +35,89 @@ pub enum Error {
     #[error("Unsupported cmake build type: {build_type}")]
     UnsupportedBuildType { build_type: String },
 
+    #[error("Unsupported compiler launcher: {launcher}")]
+    UnsupportedCompilerLauncher { launcher: String },
+
+    #[error("Unsupported linker: {linker}")]
+    UnsupportedLinker { linker: String },
+
+    #[error("Invalid memory size: {size}")]
+    InvalidMemorySize { size: String },
+
+    #[error("Invalid duration: {duration}")]
+    InvalidDuration { duration: String },
+
+    #[error("Timed out waiting for lock: {}", .path.display())]
+    LockTimeout { path: PathBuf },
+
     #[error("Configure file already exists: {path}")]
     ConfigureAlreadyExists { path: PathBuf },
 
+    #[error("Configure file not found: {path}")]
+    ConfigureNotFound { path: PathBuf },
+
+    #[error("EDITOR environmental value is not set")]
+    EditorNotSet,
+
     #[error("Failed to get LLVM version: {version}")]
     InvalidVersion { version: String },
 
     #[error("Invalid URL: {url}")]
     InvalidUrl { url: String },
 
+    #[error("Revision {rev} is not a full commit SHA")]
+    InvalidRevision { rev: String },
+
     #[error(transparent)]
     InvalidTOML {
         #[from]
         source: toml::de::Error,
     },
 
+    #[error("Invalid .llvmenv file: {}", .path.display())]
+    InvalidLocalEnv {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
     #[error("Entry {name} is invalid: {message}")]
     InvalidEntry { name: String, message: String },
 
+    #[error("Unknown tool: {name}")]
+    UnknownTool { name: String },
+
+    #[error("Cannot find C++ compiler corresponding to {path}")]
+    UnknownCompiler { path: PathBuf },
+
+    #[error("Entry not found: {name}")]
+    EntryNotFound { name: String },
+
+    #[error("Build '{name}' is not installed")]
+    BuildNotFound { name: String },
+
+    #[error("Patch {patch} cannot be applied to {}\n{output}", .dir.display())]
+    PatchFailed {
+        patch: String,
+        dir: PathBuf,
+        output: String,
+    },
+
+    #[error("Stage-1 compiler is not built: {} does not exist", .path.display())]
+    Stage1NotBuilt { path: PathBuf },
+
+    #[error("PGO training workload does not generate any profile in {}", .dir.display())]
+    ProfileNotGenerated { dir: PathBuf },
+
+    #[error("{failed} tests of {suite} failed for {name}")]
+    TestFailed {
+        name: String,
+        suite: String,
+        failed: usize,
+    },
+
+    #[error("Bisect of {entry} failed: {message}")]
+    BisectFailed { entry: String, message: String },
+
     #[error("HTTP request does not succeed with {status}: {url}")]
     HttpError {
         url: String,
@@ -73,6 +150,20 @@ pub enum Error {
         stderr: Option<String>,
     },
 
+    #[error(
+        "External command {}: {cmd}\n{tail}\nSee the full log: {}",
+        exit_status(.errno),
+        .log.display()
+    )]
+    LoggedCommandError {
+        errno: Option<i32>,
+        cmd: String,
+        /// Log file where stdout and stderr of the command are written
+        log: PathBuf,
+        /// Last lines of the output
+        tail: String,
+    },
+
     #[error("External command not found: {cmd}")]
     CommandNotFound { cmd: String },
 
@@ -84,12 +175,189 @@ pub enum Error {
     },
 }
 
+fn exit_status(errno: &Option<i32>) -> String {
+    match errno {
+        Some(errno) => format!("exit with error-code({})", errno),
+        None => "has been terminated by signal".into(),
+    }
+}
+
 impl Error {
     pub fn invalid_version(version: &str) -> Self {
         Error::InvalidVersion {
             version: version.into(),
         }
     }
+
+    /// Category of this error, which determines the exit code of `llvmenv` command
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::ConfigureAlreadyExists { .. }
+            | Error::ConfigureNotFound { .. }
+            | Error::EditorNotSet
+            | Error::InvalidTOML { .. }
+            | Error::InvalidLocalEnv { .. }
+            | Error::InvalidEntry { .. }
+            | Error::InvalidUrl { .. }
+            | Error::InvalidRevision { .. }
+            | Error::UnknownTool { .. }
+            | Error::UnknownCompiler { .. }
+            | Error::UnsupportedGenerator { .. }
+            | Error::UnsupportedBuildType { .. }
+            | Error::UnsupportedCompilerLauncher { .. }
+            | Error::UnsupportedLinker { .. }
+            | Error::InvalidMemorySize { .. }
+            | Error::Stage1NotBuilt { .. }
+            | Error::InvalidDuration { .. } => ErrorCategory::Config,
+            Error::EntryNotFound { .. } => ErrorCategory::EntryNotFound,
+            Error::BuildNotFound { .. } => ErrorCategory::BuildNotFound,
+            Error::HttpError { .. } | Error::ReqwestError { .. } => ErrorCategory::Network,
+            Error::CommandNotFound { .. } => ErrorCategory::CommandNotFound,
+            Error::CommandError { .. }
+            | Error::LoggedCommandError { .. }
+            | Error::PatchFailed { .. }
+            | Error::BisectFailed { .. }
+            | Error::ProfileNotGenerated { .. }
+            | Error::TestFailed { .. }
+            | Error::CommandTerminatedBySignal { .. } => ErrorCategory::CommandFailed,
+            Error::FileIo { .. }
+            | Error::FileIoExtra { .. }
+            | Error::IoError { .. }
+            | Error::LockTimeout { .. } => ErrorCategory::Io,
+            Error::UnsupportedOS | Error::InvalidVersion { .. } | Error::ParseIntError { .. } => {
+                ErrorCategory::Other
+            }
+        }
+    }
+
+    /// Suggestion to resolve this error
+    pub fn hint(&self) -> Option<String> {
+        Some(match self {
+            Error::ConfigureAlreadyExists { .. } => "Edit existing setting by `llvmenv edit`".into(),
+            Error::ConfigureNotFound { .. } => "Create default setting by `llvmenv init`".into(),
+            Error::EditorNotSet => {
+                "Set EDITOR environmental value, e.g. `export EDITOR=vim`".into()
+            }
+            Error::InvalidTOML { .. } | Error::InvalidEntry { .. } => {
+                "Fix entry.toml by `llvmenv edit`".into()
+            }
+            Error::LockTimeout { .. } => {
+                "Another llvmenv process is fetching into the git mirror. Wait for it, or stop it".into()
+            }
+            Error::InvalidRevision { .. } => {
+                "Write the 40-digit SHA, e.g. by `git rev-parse <rev>`, or pin a tag by `tag`".into()
+            }
+            Error::InvalidLocalEnv { .. } => {
+                "Write only the name of a build, or fix the TOML of the file".into()
+            }
+            Error::UnknownTool { .. } => {
+                "Specify `relative_path` of the tool explicitly in entry.toml".into()
+            }
+            Error::UnknownCompiler { .. } => {
+                "Use clang or gcc, or set CMAKE_C_COMPILER and CMAKE_CXX_COMPILER by `option`"
+                    .into()
+            }
+            Error::EntryNotFound { .. } => "See `llvmenv entries` for available entries".into(),
+            Error::BuildNotFound { name } => format!(
+                "Build it by `llvmenv build-entry {}`, or see `llvmenv builds` for installed builds",
+                name
+            ),
+            Error::UnsupportedGenerator { .. } => {
+                "Run `cmake --help` to see the generators available on this platform".into()
+            }
+            Error::UnsupportedBuildType { .. } => {
+                "Use one of Debug, Release, RelWithDebInfo, or MinSizeRel".into()
+            }
+            Error::UnsupportedCompilerLauncher { .. } => {
+                "Use one of ccache, sccache, none, or auto".into()
+            }
+            Error::UnsupportedLinker { .. } => "Use one of lld, gold, mold, system, or auto".into(),
+            Error::InvalidMemorySize { .. } => "Use a size with K, M, G, or T suffix, e.g. 64G".into(),
+            Error::InvalidDuration { .. } => {
+                "Use a duration with s, m, h, d, or w suffix, e.g. 30d".into()
+            }
+            Error::CommandNotFound { .. } => "Install the command and add it to PATH".into(),
+            Error::PatchFailed { .. } => {
+                "Update the patch for the source, or fix `patch_level` of the entry".into()
+            }
+            Error::Stage1NotBuilt { .. } => {
+                "Build it in stage 1, e.g. `bootstrap.option = { LLVM_ENABLE_PROJECTS = \"clang;lld\" }`, \
+                 or add it to `tools` of the entry"
+                    .into()
+            }
+            Error::ProfileNotGenerated { .. } => {
+                "Build compiler-rt in stage 1 for the profile runtime, \
+                 and check that `pgo_training` compiles by $CC and $CXX"
+                    .into()
+            }
+            Error::TestFailed { .. } => {
+                "See the failed tests in the logs by `llvmenv logs <entry>`".into()
+            }
+            Error::BisectFailed { .. } => {
+                "See `git bisect log` in the source directory of the entry".into()
+            }
+            _ => return None,
+        })
+    }
+
+    /// Human readable report of this error including its causes and hint
+    ///
+    /// ```
+    /// use llvmenv::error::Error;
+    /// let e = Error::BuildNotFound { name: "10.0.0".into() };
+    /// assert!(e.report().starts_with("error: Build '10.0.0' is not installed\n"));
+    /// ```
+    pub fn report(&self) -> String {
+        let mut report = format!("error: {}\n", self);
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            report += &format!("  caused by: {}\n", cause);
+            source = cause.source();
+        }
+        if let Some(hint) = self.hint() {
+            report += &format!("hint: {}\n", hint);
+        }
+        report
+    }
+}
+
+/// Category of [enum@Error], which determines the exit code of `llvmenv` command
+///
+/// | Exit code | Category        | Description                                    |
+/// |:---------:|:----------------|:-----------------------------------------------|
+/// | 1         | Other           | Unexpected errors                              |
+/// | 2         | Config          | Invalid or missing setting, e.g. entry.toml    |
+/// | 3         | EntryNotFound   | Entry is not defined                           |
+/// | 4         | BuildNotFound   | Build is not installed                         |
+/// | 5         | Network         | Failed to download resources                   |
+/// | 6         | CommandFailed   | External command (e.g. cmake, git) failed      |
+/// | 7         | CommandNotFound | External command is not installed              |
+/// | 8         | Io              | Failed to access files                         |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Other,
+    Config,
+    EntryNotFound,
+    BuildNotFound,
+    Network,
+    CommandFailed,
+    CommandNotFound,
+    Io,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Other => 1,
+            ErrorCategory::Config => 2,
+            ErrorCategory::EntryNotFound => 3,
+            ErrorCategory::BuildNotFound => 4,
+            ErrorCategory::Network => 5,
+            ErrorCategory::CommandFailed => 6,
+            ErrorCategory::CommandNotFound => 7,
+            ErrorCategory::Io => 8,
+        }
+    }
 }
 
 pub trait FileIoConvert<T> {
@@ -109,6 +377,37 @@ pub trait CommandExt {
     fn silent(&mut self) -> &mut Self;
     fn check_run(&mut self) -> Result<()>;
     fn check_output(&mut self) -> Result<(String, String)>;
+    /// Run command while writing both stdout and stderr into the terminal and the `log` file
+    fn check_run_logged(&mut self, log: &Path) -> Result<()>;
+}
+
+struct LogSink {
+    file: fs::File,
+    tail: VecDeque<String>,
+}
+
+fn tee(
+    src: impl Read + Send + 'static,
+    mut dest: impl Write + Send + 'static,
+    sink: Arc<Mutex<LogSink>>,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut src = BufReader::new(src);
+        let mut line = Vec::new();
+        while src.read_until(b'\n', &mut line)? > 0 {
+            // Terminal may be closed, but the log must be kept
+            let _ = dest.write_all(&line).and_then(|_| dest.flush());
+            let mut sink = sink.lock().unwrap();
+            sink.file.write_all(&line)?;
+            if sink.tail.len() == LOG_TAIL_LINES {
+                sink.tail.pop_front();
+            }
+            sink.tail
+                .push_back(String::from_utf8_lossy(&line).trim_end().to_string());
+            line.clear();
+        }
+        Ok(())
+    })
 }
 
 impl CommandExt for process::Command {
@@ -170,4 +469,72 @@ impl CommandExt for process::Command {
             }),
         }
     }
+
+    fn check_run_logged(&mut self, log: &Path) -> Result<()> {
+        let cmd = format!("{:?}", self);
+        let mut file = fs::File::create(log).with(log)?;
+        writeln!(file, "$ {}", cmd).with(log)?;
+        let mut child = self
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()
+            .map_err(|_| Error::CommandNotFound { cmd: cmd.clone() })?;
+        let sink = Arc::new(Mutex::new(LogSink {
+            file,
+            tail: VecDeque::new(),
+        }));
+        let stdout = tee(child.stdout.take().unwrap(), io::stdout(), sink.clone());
+        let stderr = tee(child.stderr.take().unwrap(), io::stderr(), sink.clone());
+        let st = child.wait().with(log)?;
+        for handle in [stdout, stderr] {
+            handle.join().expect("Log writer panicked").with(log)?;
+        }
+        if st.success() {
+            return Ok(());
+        }
+        let tail = sink.lock().unwrap().tail.iter().join("\n");
+        Err(Error::LoggedCommandError {
+            errno: st.code(),
+            cmd,
+            log: log.into(),
+            tail,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_run_logged() -> Result<()> {
+        let tmp_dir = tempfile::TempDir::new().with("/tmp")?;
+        let log = tmp_dir.path().join("test.log");
+        let err = process::Command::new("sh")
+            .args(["-c", "echo out; echo err >&2; exit 3"])
+            .check_run_logged(&log)
+            .unwrap_err();
+        match err {
+            Error::LoggedCommandError { errno, tail, .. } => {
+                assert_eq!(errno, Some(3));
+                assert!(tail.contains("out") && tail.contains("err"));
+            }
+            _ => panic!("Unexpected error: {:?}", err),
+        }
+        let content = fs::read_to_string(&log).with(&log)?;
+        assert!(content.contains("out\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_causes() {
+        let e: Result<()> =
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such file")).with("/nonexistent");
+        let e = e.unwrap_err();
+        assert_eq!(e.category(), ErrorCategory::Io);
+        assert_eq!(
+            e.report(),
+            "error: IO error while accessing /nonexistent\n  caused by: no such file\n"
+        );
+    }
 }
@@ -0,0 +1,374 @@
+//! Disk usage and garbage collection of cache and data directories
+//!
+//! `llvmenv du` shows the disk usage of each entry:
+//!
+//! - source: Source directory of the remote entry in `$XDG_CACHE_HOME/llvmenv/{name}` (local sources are not counted)
+//! - build: Build directories of the builds of the entry, including intermediate stages,
+//!   and the build directory in the source tree used by old llvmenv
+//! - mirror: Shared git mirror of the URL of the entry in `$XDG_CACHE_HOME/llvmenv/git`
+//! - install: Builds installed by the entry in `$XDG_DATA_HOME/llvmenv`
+//!
+//! `llvmenv gc` removes the build directories of the builds not used recently,
+//! and the sources (and the build directory of `llvmenv bisect`) of the entries whose builds are all unused.
+//! Build directories in the sources of remote entries used by old llvmenv are also removed.
+//! A build is used when it is installed, set by `llvmenv global` or `llvmenv local`,
+//! resolved by `llvmenv prefix` (e.g. by the Zsh integration), or its executables are accessed.
+//!
+//! The builds themselves are also removed unless `--keep-builds` is set,
+//! but builds used by the global `.llvmenv` or known local `.llvmenv` files are never removed.
+//! `.llvmenv` files are known if written by `llvmenv local`, or found in the current directory and its ancestors.
+//! Since `.llvmenv` files written by old llvmenv are unknown, builds are kept until `llvmenv local` is run once,
+//! and builds installed by old llvmenv are kept until they are used.
+
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    bisect::{bisect_build_dir, bisect_src_dir},
+    build::{prune_local_envs, referenced_builds, registered_local_envs, Build},
+    config::data_dir,
+    entry::{build_root, load_entries, with_stage_dirs, Entry},
+    error::*,
+    resource::{mirror_dir, Resource},
+};
+
+/// Disk usage of an entry in bytes
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Usage {
+    pub entry: String,
+    /// Source directory of the remote entry
+    pub source: u64,
+    /// Build directories
+    pub build: u64,
+    /// Shared git mirror, which may be counted for several entries
+    pub mirror: u64,
+    /// Installed builds
+    pub install: u64,
+    /// Build directory in the source tree used by old llvmenv, counted as a build directory
+    pub legacy_build_dir: Option<PathBuf>,
+}
+
+/// Total size of files in the directory. Symbolic links are not followed.
+pub fn dir_size(path: &Path) -> Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with(path),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path).with(path)? {
+        size += dir_size(&entry.with(path)?.path())?;
+    }
+    Ok(size)
+}
+
+/// Human readable size, e.g. `1.5G`
+pub fn format_size(size: u64) -> String {
+    let mut value = size as f64;
+    for unit in ["B", "K", "M", "G"] {
+        if value < 1024.0 {
+            return if unit == "B" {
+                format!("{}B", size)
+            } else {
+                format!("{:.1}{}", value, unit)
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1}T", value)
+}
+
+/// Parse a duration with `s`, `m`, `h`, `d`, or `w` suffix, e.g. `30d`
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidDuration {
+        duration: duration.into(),
+    };
+    let trimmed = duration.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (value, unit) = trimmed.split_at(split);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(value * seconds))
+}
+
+/// Installed builds with the names of their entries. Builds not installed by `llvmenv build-entry` are ignored.
+/// Builds without `bin`, e.g. only libraries are installed, are also listed unlike [builds](crate::build::builds).
+fn installed_builds() -> Result<Vec<(Build, String)>> {
+    let data_dir = data_dir()?;
+    let mut prefixes = Vec::new();
+    for entry in fs::read_dir(&data_dir).with(&data_dir)? {
+        prefixes.push(entry.with(&data_dir)?.path());
+    }
+    prefixes.sort();
+    let mut installed = Vec::new();
+    for prefix in prefixes.iter().filter(|prefix| prefix.is_dir()) {
+        let build = Build::from_path(prefix);
+        if let Some(metadata) = build.metadata()? {
+            installed.push((build, metadata.entry));
+        }
+    }
+    Ok(installed)
+}
+
+/// Names of the builds of the entry, installed or not
+fn build_names(entry: &Entry, installed: &[(Build, String)]) -> Result<Vec<String>> {
+    let mut names = vec![entry.install_name()?];
+    for (build, _) in installed.iter().filter(|(_, name)| name == entry.name()) {
+        if !names.iter().any(|name| name == build.name()) {
+            names.push(build.name().into());
+        }
+    }
+    Ok(names)
+}
+
+/// Build directories of the build, including intermediate stages
+fn build_dirs(name: &str) -> Result<Vec<PathBuf>> {
+    Ok(with_stage_dirs(&build_root()?.join(name)))
+}
+
+fn git_mirror(entry: &Entry) -> Result<Option<PathBuf>> {
+    Ok(match entry {
+        Entry::Remote { url, .. } => match Resource::from_url(url) {
+            Ok(Resource::Git { url, .. }) => Some(mirror_dir(&url)?),
+            _ => None,
+        },
+        Entry::Local { .. } => None,
+    })
+}
+
+/// Disk usage of entries which use any disk space
+pub fn disk_usage() -> Result<Vec<Usage>> {
+    let installed = installed_builds()?;
+    let mut usages = Vec::new();
+    for entry in load_entries()? {
+        let mut usage = Usage {
+            entry: entry.name().into(),
+            ..Default::default()
+        };
+        usage.legacy_build_dir = entry.legacy_build_dir()?;
+        let legacy = match &usage.legacy_build_dir {
+            Some(dir) => dir_size(dir)?,
+            None => 0,
+        };
+        if let Entry::Remote { .. } = entry {
+            usage.source = dir_size(&entry.src_dir()?)?.saturating_sub(legacy);
+        }
+        usage.build = legacy;
+        let mut dirs = vec![
+            entry.build_dir_path()?,
+            bisect_build_dir(&entry)?,
+            bisect_src_dir(&entry)?,
+        ];
+        for name in build_names(&entry, &installed)? {
+            dirs.extend(build_dirs(&name)?);
+            usage.install += dir_size(Build::from_name(&name)?.prefix())?;
+        }
+        dirs.sort();
+        dirs.dedup();
+        for dir in &dirs {
+            usage.build += dir_size(dir)?;
+        }
+        if usage.source > 0 {
+            if let Some(mirror) = git_mirror(&entry)? {
+                usage.mirror = dir_size(&mirror)?;
+            }
+        }
+        if usage.source + usage.build + usage.mirror + usage.install > 0 {
+            usages.push(usage);
+        }
+    }
+    Ok(usages)
+}
+
+/// Options of [gc]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcOptions {
+    /// Keep installed builds, and remove only build directories and sources
+    pub keep_builds: bool,
+    /// Builds not used for this duration are collected
+    pub older_than: Duration,
+    /// Only list paths to be removed
+    pub dry_run: bool,
+}
+
+/// Last access to the executables of the build.
+/// The modification time of the prefix is used if it has no `bin`, e.g. only libraries are installed.
+fn last_accessed(build: &Build) -> Result<SystemTime> {
+    let bin = build.prefix().join("bin");
+    let entries = match fs::read_dir(&bin) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let prefix = build.prefix();
+            return fs::metadata(prefix)
+                .and_then(|metadata| metadata.modified())
+                .with(prefix);
+        }
+        Err(e) => return Err(e).with(&bin),
+    };
+    let mut last = SystemTime::UNIX_EPOCH;
+    for entry in entries {
+        let metadata = entry.with(&bin)?.metadata().with(&bin)?;
+        if let Ok(accessed) = metadata.accessed().or_else(|_| metadata.modified()) {
+            last = last.max(accessed);
+        }
+    }
+    Ok(last)
+}
+
+/// Last time when the build is used. `None` if the use has never been recorded, e.g. installed by old llvmenv.
+fn last_used(build: &Build) -> Result<Option<SystemTime>> {
+    Ok(match build.last_used()? {
+        Some(recorded) => Some(recorded.max(last_accessed(build)?)),
+        None => None,
+    })
+}
+
+/// Remove build directories, sources, and builds not used recently. Returns the removed paths with their sizes.
+pub fn gc(opts: &GcOptions) -> Result<Vec<(PathBuf, u64)>> {
+    let now = SystemTime::now();
+    if !opts.dry_run {
+        prune_local_envs()?;
+    }
+    let keep_builds = opts.keep_builds || registered_local_envs()?.is_none();
+    if keep_builds && !opts.keep_builds {
+        warn!("Keep builds since `.llvmenv` files may be unknown. Run `llvmenv local` where `.llvmenv` is used, and retry");
+    }
+    let referenced: HashSet<String> = referenced_builds()?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let installed = installed_builds()?;
+    let mut unused = HashSet::new();
+    let mut garbage = Vec::new();
+    for (build, _) in &installed {
+        let last_used = last_used(build)?;
+        // Build directories are collected by the access to executables even if the use is not recorded
+        let last = match last_used {
+            Some(last_used) => last_used,
+            None => last_accessed(build)?,
+        };
+        if !now
+            .duration_since(last)
+            .is_ok_and(|age| age > opts.older_than)
+        {
+            continue;
+        }
+        unused.insert(build.name().to_string());
+        garbage.extend(build_dirs(build.name())?);
+        if keep_builds || referenced.contains(build.name()) {
+            continue;
+        }
+        if last_used.is_some() {
+            garbage.push(build.prefix().to_path_buf());
+        } else {
+            warn!(
+                "Keep {} since its use is not recorded yet. `llvmenv prefix` records the use of the current build",
+                build.name()
+            );
+        }
+    }
+    let mut mirrors = Vec::new();
+    for entry in load_entries()? {
+        if let (Entry::Remote { .. }, Some(dir)) = (&entry, entry.legacy_build_dir()?) {
+            garbage.push(dir);
+        }
+        let builds: Vec<&Build> = installed
+            .iter()
+            .filter(|(_, name)| name == entry.name())
+            .map(|(build, _)| build)
+            .collect();
+        if builds.is_empty() || !builds.iter().all(|build| unused.contains(build.name())) {
+            continue;
+        }
+        garbage.push(bisect_build_dir(&entry)?);
+        garbage.push(bisect_src_dir(&entry)?);
+        if matches!(entry, Entry::Remote { .. }) && entry.src_dir()?.exists() {
+            garbage.push(entry.src_dir()?);
+            if let Some(mirror) = git_mirror(&entry)? {
+                mirrors.push(mirror);
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    for path in garbage {
+        if !path.exists() || removed.iter().any(|(p, _)| p == &path) {
+            continue;
+        }
+        let size = dir_size(&path)?;
+        if !opts.dry_run {
+            info!("Remove {}", path.display());
+            fs::remove_dir_all(&path).with(&path)?;
+        }
+        removed.push((path, size));
+    }
+    if !opts.dry_run {
+        // Forget worktrees of removed sources
+        for mirror in mirrors.iter().filter(|mirror| mirror.exists()) {
+            Command::new("git")
+                .args(["worktree", "prune"])
+                .current_dir(mirror)
+                .check_run()?;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn sizes() -> Result<()> {
+        let tmp = TempDir::new()?;
+        let dir = tmp.path().join("build");
+        fs::create_dir_all(dir.join("bin"))?;
+        fs::write(dir.join("bin").join("clang"), vec![0u8; 3000])?;
+        fs::write(dir.join("CMakeCache.txt"), vec![0u8; 100])?;
+        assert_eq!(dir_size(&dir)?, 3100);
+        assert_eq!(dir_size(&tmp.path().join("nonexistent"))?, 0);
+
+        assert_eq!(format_size(100), "100B");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(50 << 30), "50.0G");
+        assert_eq!(format_size(3 << 40), "3.0T");
+        Ok(())
+    }
+
+    #[test]
+    fn duration() -> Result<()> {
+        assert_eq!(
+            parse_duration("30d")?,
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_duration("12h")?, Duration::from_secs(12 * 60 * 60));
+        assert_eq!(
+            parse_duration("2w")?,
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_duration("0s")?, Duration::from_secs(0));
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("30y").is_err());
+        Ok(())
+    }
+}
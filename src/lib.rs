@@ -1,5 +1,7 @@
+pub mod bisect;
 pub mod build;
 pub mod config;
 pub mod entry;
 pub mod error;
+pub mod gc;
 pub mod resource;
@@ -0,0 +1,135 @@
+// Default training workload for PGO builds of clang.
+//
+// This file is compiled by the instrumented clang with several optimization levels
+// to collect the profile. It should exercise the frontend (templates, overload resolution,
+// lambdas) and the optimizer (inlining, loops, vectorization) like typical C++ code.
+
+#include <algorithm>
+#include <functional>
+#include <iostream>
+#include <map>
+#include <memory>
+#include <numeric>
+#include <sstream>
+#include <string>
+#include <unordered_map>
+#include <utility>
+#include <vector>
+
+namespace training {
+
+template <typename T> struct Matrix {
+  std::size_t rows, cols;
+  std::vector<T> data;
+
+  Matrix(std::size_t rows, std::size_t cols)
+      : rows(rows), cols(cols), data(rows * cols) {}
+
+  T &operator()(std::size_t i, std::size_t j) { return data[i * cols + j]; }
+  const T &operator()(std::size_t i, std::size_t j) const {
+    return data[i * cols + j];
+  }
+
+  Matrix operator*(const Matrix &other) const {
+    Matrix result(rows, other.cols);
+    for (std::size_t i = 0; i < rows; ++i)
+      for (std::size_t k = 0; k < cols; ++k)
+        for (std::size_t j = 0; j < other.cols; ++j)
+          result(i, j) += (*this)(i, k) * other(k, j);
+    return result;
+  }
+};
+
+class Shape {
+public:
+  virtual ~Shape() = default;
+  virtual double area() const = 0;
+  virtual std::string name() const = 0;
+};
+
+class Circle : public Shape {
+  double r;
+
+public:
+  explicit Circle(double r) : r(r) {}
+  double area() const override { return 3.14159265358979 * r * r; }
+  std::string name() const override { return "circle"; }
+};
+
+class Rect : public Shape {
+  double w, h;
+
+public:
+  Rect(double w, double h) : w(w), h(h) {}
+  double area() const override { return w * h; }
+  std::string name() const override { return "rect"; }
+};
+
+template <typename Container, typename F>
+auto map_values(const Container &c, F f) -> std::vector<decltype(f(*c.begin()))> {
+  std::vector<decltype(f(*c.begin()))> out;
+  out.reserve(c.size());
+  std::transform(c.begin(), c.end(), std::back_inserter(out), f);
+  return out;
+}
+
+template <typename... Args> std::string concat(const Args &...args) {
+  std::ostringstream os;
+  int dummy[] = {0, ((os << args), 0)...};
+  (void)dummy;
+  return os.str();
+}
+
+std::map<std::string, int> word_count(const std::string &text) {
+  std::map<std::string, int> counts;
+  std::istringstream is(text);
+  std::string word;
+  while (is >> word)
+    ++counts[word];
+  return counts;
+}
+
+unsigned long fib(unsigned n) {
+  std::unordered_map<unsigned, unsigned long> memo;
+  std::function<unsigned long(unsigned)> go = [&](unsigned k) -> unsigned long {
+    if (k < 2)
+      return k;
+    auto it = memo.find(k);
+    if (it != memo.end())
+      return it->second;
+    return memo[k] = go(k - 1) + go(k - 2);
+  };
+  return go(n);
+}
+
+} // namespace training
+
+int main() {
+  using namespace training;
+
+  Matrix<double> a(16, 16), b(16, 16);
+  for (std::size_t i = 0; i < 16; ++i)
+    for (std::size_t j = 0; j < 16; ++j) {
+      a(i, j) = static_cast<double>(i + j);
+      b(i, j) = static_cast<double>(i) - static_cast<double>(j);
+    }
+  auto c = a * b;
+
+  std::vector<std::unique_ptr<Shape>> shapes;
+  for (int i = 0; i < 10; ++i) {
+    shapes.push_back(std::make_unique<Circle>(i));
+    shapes.push_back(std::make_unique<Rect>(i, i + 1));
+  }
+  std::sort(shapes.begin(), shapes.end(),
+            [](const std::unique_ptr<Shape> &x, const std::unique_ptr<Shape> &y) {
+              return x->area() < y->area();
+            });
+  auto areas = map_values(shapes, [](const std::unique_ptr<Shape> &s) { return s->area(); });
+  double total = std::accumulate(areas.begin(), areas.end(), 0.0);
+
+  auto counts = word_count("the quick brown fox jumps over the lazy dog the end");
+  std::cout << concat("total=", total, " c00=", c(0, 0), " the=", counts["the"],
+                      " fib=", fib(50))
+            << std::endl;
+  return 0;
+}
@@ -6,11 +6,18 @@ use futures::{
 };
 use indicatif::{ProgressBar, ProgressStyle};
 use log::*;
-use std::{fs, io, path::*, process::Command};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io,
+    path::*,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
 use tempfile::TempDir;
 use url::Url;
 
-use crate::error::*;
+use crate::{config::*, error::*};
 
 /// Remote LLVM/Clang resource
 #[derive(Debug, PartialEq)]
@@ -18,7 +25,15 @@ pub enum Resource {
     /// Remote Subversion repository
     Svn { url: String },
     /// Remote Git repository
-    Git { url: String, branch: Option<String> },
+    ///
+    /// `rev` pins the repository to a full commit SHA or a ref (e.g. `refs/tags/llvmorg-13.0.0`),
+    /// which is fetched shallowly instead of the branch.
+    /// Abbreviated SHAs cannot be fetched shallowly, and are rejected.
+    Git {
+        url: String,
+        branch: Option<String>,
+        rev: Option<String>,
+    },
     /// Tar archive
     Tar { url: String },
 }
@@ -41,7 +56,7 @@ impl Resource {
     /// # use llvmenv::resource::Resource;
     /// let github_mirror = "https://github.com/llvm/llvm-project";
     /// let git = Resource::from_url(github_mirror).unwrap();
-    /// assert_eq!(git, Resource::Git { url: github_mirror.into(), branch: None });
+    /// assert_eq!(git, Resource::Git { url: github_mirror.into(), branch: None, rev: None });
     /// ```
     ///
     /// - Tar Archive
@@ -52,6 +67,18 @@ impl Resource {
     /// let tar = Resource::from_url(tar_url).unwrap();
     /// assert_eq!(tar, Resource::Tar { url: tar_url.into() });
     /// ```
+    ///
+    /// - Fragment of Git URL specifies a branch, or a commit (`#rev=<sha>`) or a tag (`#tag=<name>`) to be pinned
+    ///
+    /// ```
+    /// # use llvmenv::resource::Resource;
+    /// let git = Resource::from_url("https://github.com/llvm/llvm-project#tag=llvmorg-13.0.0").unwrap();
+    /// assert_eq!(git, Resource::Git {
+    ///     url: "https://github.com/llvm/llvm-project".into(),
+    ///     branch: None,
+    ///     rev: Some("refs/tags/llvmorg-13.0.0".into()),
+    /// });
+    /// ```
     pub fn from_url(url_str: &str) -> Result<Self> {
         // Check file extension
         if let Ok(filename) = get_filename_from_url(url_str) {
@@ -73,10 +100,7 @@ impl Resource {
 
             if filename.ends_with(".git") {
                 debug!("Find '.git' extension");
-                return Ok(Resource::Git {
-                    url: strip_branch_from_url(url_str)?,
-                    branch: get_branch_from_url(url_str)?,
-                });
+                return git_from_url(url_str);
             }
         }
 
@@ -87,10 +111,7 @@ impl Resource {
         for service in &["github.com", "gitlab.com"] {
             if url.host_str() == Some(service) {
                 debug!("URL is a cloud git service: {}", service);
-                return Ok(Resource::Git {
-                    url: strip_branch_from_url(url_str)?,
-                    branch: get_branch_from_url(url_str)?,
-                });
+                return git_from_url(url_str);
             }
         }
 
@@ -103,10 +124,7 @@ impl Resource {
             }
             if url.path().starts_with("/git") {
                 debug!("URL is LLVM Git repository");
-                return Ok(Resource::Git {
-                    url: strip_branch_from_url(url_str)?,
-                    branch: get_branch_from_url(url_str)?,
-                });
+                return git_from_url(url_str);
             }
         }
 
@@ -128,23 +146,20 @@ impl Resource {
             .silent()
             .check_run()?;
         Command::new("git")
-            .args(&["remote", "add", "origin"])
+            .args(["remote", "add", "origin"])
             .arg(url_str)
             .current_dir(tmp_dir.path())
             .silent()
             .check_run()?;
         match Command::new("git")
-            .args(&["ls-remote"])
+            .args(["ls-remote"])
             .current_dir(tmp_dir.path())
             .silent()
             .check_run()
         {
             Ok(_) => {
                 debug!("Git access succeeds");
-                Ok(Resource::Git {
-                    url: strip_branch_from_url(url_str)?,
-                    branch: get_branch_from_url(url_str)?,
-                })
+                git_from_url(url_str)
             }
             Err(_) => {
                 debug!("Git access failed. Regarded as a SVN repository.");
@@ -156,26 +171,45 @@ impl Resource {
     }
 
     pub fn download(&self, dest: &Path) -> Result<()> {
+        self.download_with_mirrors(dest, &mirror_root()?)
+    }
+
+    /// Download into `dest`. Git repositories are fetched into the mirrors in `mirrors`.
+    fn download_with_mirrors(&self, dest: &Path, mirrors: &Path) -> Result<()> {
         if !dest.exists() {
             fs::create_dir_all(dest).with(dest)?;
         }
         if !dest.is_dir() {
-            return Err(io::Error::new(io::ErrorKind::Other, "Not a directory")).with(dest);
+            return Err(io::Error::other("Not a directory")).with(dest);
         }
         match self {
             Resource::Svn { url, .. } => Command::new("svn")
-                .args(&["co", url.as_str(), "-r", "HEAD"])
+                .args(["co", url.as_str(), "-r", "HEAD"])
                 .arg(dest)
                 .check_run()?,
-            Resource::Git { url, branch } => {
-                info!("Git clone {}", url);
-                let mut git = Command::new("git");
-                git.args(&["clone", url.as_str(), "-q", "--depth", "1"])
-                    .arg(dest);
-                if let Some(branch) = branch {
-                    git.args(&["-b", branch]);
+            Resource::Git { url, branch, rev } => {
+                if dest.join(".git").exists() {
+                    // Already checked out. Move to the pinned revision if it is changed.
+                    if let Some(rev) = rev {
+                        if resolve_rev(dest, rev) != resolve_rev(dest, "HEAD") {
+                            fetch_rev(dest, url, rev)?;
+                        }
+                    }
+                    return Ok(());
                 }
-                git.check_run()?;
+                let target = rev.as_ref().or(branch.as_ref()).map_or("HEAD", |r| r);
+                info!("Git fetch {} {}", url, target);
+                let mirror = open_mirror(mirrors, url)?;
+                let commit = fetch_commit(&mirror, Some(&mirror), url, target)?;
+                let _lock = FileLock::acquire(&mirror)?;
+                // Forget worktrees whose directories have been removed, e.g. by `--discard`
+                git(&mirror, &["worktree", "prune"])?;
+                Command::new("git")
+                    .args(["worktree", "add", "-q", "--detach"])
+                    .arg(dest)
+                    .arg(&commit)
+                    .current_dir(&mirror)
+                    .check_run()?;
             }
             Resource::Tar { url } => {
                 info!("Download Tar file: {}", url);
@@ -184,13 +218,11 @@ impl Resource {
                 let mut bytes = rt.block_on(download(url))?;
                 let xz_buf = xz2::read::XzDecoder::new(&mut bytes);
                 let mut tar_buf = tar::Archive::new(xz_buf);
-                let entries = tar_buf
-                    .entries()
-                    .expect("Tar archive does not contains entry");
+                let entries = tar_buf.entries().with(dest)?;
 
                 for entry in entries {
-                    let mut entry = entry.expect("Invalid entry");
-                    let path = entry.path().expect("Filename is not a valid unicode");
+                    let mut entry = entry.with(dest)?;
+                    let path = entry.path().with(dest)?.into_owned();
                     let mut target = dest.to_owned();
                     for comp in path.components().skip(1) {
                         target = target.join(comp);
@@ -213,16 +245,217 @@ impl Resource {
                 .arg("update")
                 .current_dir(dest)
                 .check_run()?,
-            Resource::Git { .. } => Command::new("git")
-                .arg("pull")
-                .current_dir(dest)
-                .check_run()?,
+            Resource::Git { url, branch, rev } => {
+                // Fetch the tip of the branch (or of the remote HEAD) instead of `git pull`,
+                // which cannot follow a moved branch in a shallow clone
+                let current = Command::new("git")
+                    .args(["symbolic-ref", "-q", "--short", "HEAD"])
+                    .current_dir(dest)
+                    .check_output()
+                    .ok()
+                    .map(|(stdout, _)| stdout.trim().to_string());
+                let target = rev.as_ref().or(branch.as_ref()).or(current.as_ref());
+                fetch_rev(dest, url, target.map_or("HEAD", |r| r))?;
+            }
             Resource::Tar { .. } => {}
         }
         Ok(())
     }
 }
 
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    Command::new("git").args(args).current_dir(dir).check_run()
+}
+
+/// Run git with an identity to commit in tests, and returns the trimmed stdout
+#[cfg(test)]
+pub(crate) fn test_git(dir: &Path, args: &[&str]) -> Result<String> {
+    Command::new("git")
+        .args([
+            "-c",
+            "user.name=llvmenv",
+            "-c",
+            "user.email=llvmenv@example.com",
+        ])
+        .args(args)
+        .current_dir(dir)
+        .check_output()
+        .map(|(stdout, _)| stdout.trim().to_string())
+}
+
+/// Commit SHA of a revision in the local repository
+fn resolve_rev(dir: &Path, rev: &str) -> Option<String> {
+    Command::new("git")
+        .args(["rev-parse", "-q", "--verify"])
+        .arg(format!("{}^{{commit}}", rev))
+        .current_dir(dir)
+        .check_output()
+        .ok()
+        .map(|(stdout, _)| stdout.trim().to_string())
+}
+
+/// Mirror sharing objects and refs with the checkout if it is a worktree.
+/// `None` for a standalone clone, e.g. checked out by old llvmenv.
+fn shared_mirror(dir: &Path) -> Result<Option<PathBuf>> {
+    let (stdout, _) = Command::new("git")
+        .args(["rev-parse", "--git-dir", "--git-common-dir"])
+        .current_dir(dir)
+        .check_output()?;
+    let mut lines = stdout.lines().map(|line| dir.join(line.trim()));
+    Ok(match (lines.next(), lines.next()) {
+        (Some(git_dir), Some(common_dir)) if git_dir != common_dir => Some(common_dir),
+        _ => None,
+    })
+}
+
+/// Fetch a single commit shallowly, and checkout it as a detached HEAD.
+/// Local changes (e.g. applied patches) are discarded if HEAD moves.
+fn fetch_rev(dir: &Path, url: &str, rev: &str) -> Result<()> {
+    let commit = fetch_commit(dir, shared_mirror(dir)?.as_deref(), url, rev)?;
+    if resolve_rev(dir, "HEAD").as_ref() != Some(&commit) {
+        git(dir, &["checkout", "-q", "--force", "--detach", &commit])?;
+    }
+    Ok(())
+}
+
+/// Fetch a revision shallowly into the repository (the mirror or its worktree), and returns its commit SHA.
+/// The mirror is locked if given, since objects and refs are shared with the mirror even in worktrees.
+fn fetch_commit(dir: &Path, mirror: Option<&Path>, url: &str, rev: &str) -> Result<String> {
+    let _lock = mirror.map(FileLock::acquire).transpose()?;
+    // Fetch refs (e.g. tags) into the same name to be resolved locally next time
+    let (refspec, target) = if rev.starts_with("refs/") {
+        (format!("+{0}:{0}", rev), rev)
+    } else {
+        (rev.to_string(), "FETCH_HEAD")
+    };
+    git(dir, &["fetch", "-q", "--depth", "1", "origin", &refspec])?;
+    resolve_rev(dir, target).ok_or_else(|| Error::InvalidUrl {
+        url: format!("{}#{}", url, rev),
+    })
+}
+
+/// The git repository is a shallow clone
+pub(crate) fn is_shallow(dir: &Path) -> Result<bool> {
+    let (shallow, _) = Command::new("git")
+        .args(["rev-parse", "--is-shallow-repository"])
+        .current_dir(dir)
+        .check_output()?;
+    Ok(shallow.trim() == "true")
+}
+
+/// Fetch the whole history of a shallow git repository checked out by llvmenv, e.g. for `git bisect`.
+/// The mirror is locked if the repository is a worktree of a shared mirror.
+pub(crate) fn unshallow(dir: &Path) -> Result<()> {
+    if !is_shallow(dir)? {
+        return Ok(());
+    }
+    let _lock = shared_mirror(dir)?
+        .as_deref()
+        .map(FileLock::acquire)
+        .transpose()?;
+    info!("Fetch the whole history of {}", dir.display());
+    git(dir, &["fetch", "-q", "--unshallow", "origin"])
+}
+
+/// Directory of git mirrors, `$XDG_CACHE_HOME/llvmenv/git`
+fn mirror_root() -> Result<PathBuf> {
+    Ok(cache_dir()?.join(GIT_MIRROR_DIR))
+}
+
+/// Bare mirror of git repository shared by all entries of the URL, `$XDG_CACHE_HOME/llvmenv/git/{name}-{hash}.git`
+pub fn mirror_dir(url: &str) -> Result<PathBuf> {
+    Ok(mirror_in(&mirror_root()?, url))
+}
+
+fn mirror_in(mirrors: &Path, url: &str) -> PathBuf {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(".git");
+    let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+    mirrors.join(format!("{}-{}.git", name, &hash[..12]))
+}
+
+fn open_mirror(mirrors: &Path, url: &str) -> Result<PathBuf> {
+    let mirror = mirror_in(mirrors, url);
+    fs::create_dir_all(mirrors).with(mirrors)?;
+    let _lock = FileLock::acquire(&mirror)?;
+    if !mirror.exists() {
+        info!("Create git mirror of {}: {}", url, mirror.display());
+        let tmp = mirror.with_extension("tmp");
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp).with(&tmp)?;
+        }
+        fs::create_dir_all(&tmp).with(&tmp)?;
+        git(&tmp, &["init", "-q", "--bare"])?;
+        git(&tmp, &["remote", "add", "origin", url])?;
+        fs::rename(&tmp, &mirror).with(&mirror)?;
+    }
+    Ok(mirror)
+}
+
+/// Time to wait for another llvmenv process using the same directory, e.g. fetching into the same mirror
+const LOCK_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Lock of a directory shared by concurrent llvmenv processes, e.g. a git mirror,
+/// an exclusive file lock of `{dir}.lock`.
+/// The lock is released by the OS when the process exits, even if it is killed.
+pub(crate) struct FileLock {
+    _file: fs::File,
+}
+
+impl FileLock {
+    pub(crate) fn acquire(dir: &Path) -> Result<Self> {
+        Self::acquire_with_timeout(dir, LOCK_TIMEOUT)
+    }
+
+    fn acquire_with_timeout(dir: &Path, timeout: Duration) -> Result<Self> {
+        let mut path = dir.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with(&path)?;
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(FileLock { _file: file }),
+                Err(fs::TryLockError::WouldBlock) => {
+                    if start.elapsed() > timeout {
+                        return Err(Error::LockTimeout { path });
+                    }
+                    if !waiting {
+                        info!("Waiting for another llvmenv process: {}", path.display());
+                        waiting = true;
+                    }
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Err(fs::TryLockError::Error(e)) => return Err(e).with(&path),
+            }
+        }
+    }
+}
+
+/// Fetch a small file such as a patch into memory
+pub fn fetch(url: &str) -> Result<Vec<u8>> {
+    info!("Fetch {}", url);
+    let res = reqwest::blocking::get(url)?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(Error::HttpError {
+            url: url.into(),
+            status,
+        });
+    }
+    Ok(res.bytes()?.to_vec())
+}
+
 struct Download<T> {
     stream: T,
     bytes: Option<bytes::Bytes>,
@@ -272,7 +505,7 @@ where
         } else {
             match self.stream.next() {
                 Some(Ok(bytes)) => bytes,
-                Some(Err(err)) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+                Some(Err(err)) => return Err(io::Error::other(err)),
                 None => return Ok(0),
             }
         };
@@ -291,15 +524,41 @@ fn get_filename_from_url(url_str: &str) -> Result<String> {
     let url = ::url::Url::parse(url_str).map_err(|_| Error::InvalidUrl {
         url: url_str.into(),
     })?;
-    let seg = url.path_segments().ok_or(Error::InvalidUrl {
+    let mut seg = url.path_segments().ok_or(Error::InvalidUrl {
         url: url_str.into(),
     })?;
-    let filename = seg.last().ok_or(Error::InvalidUrl {
+    let filename = seg.next_back().ok_or(Error::InvalidUrl {
         url: url_str.into(),
     })?;
     Ok(filename.to_string())
 }
 
+fn git_from_url(url_str: &str) -> Result<Resource> {
+    let url = strip_branch_from_url(url_str)?;
+    let (branch, rev) = match get_branch_from_url(url_str)? {
+        Some(fragment) => {
+            if let Some(rev) = fragment.strip_prefix("rev=") {
+                (None, Some(commit_sha(rev)?))
+            } else if let Some(tag) = fragment.strip_prefix("tag=") {
+                (None, Some(format!("refs/tags/{}", tag)))
+            } else {
+                (Some(fragment), None)
+            }
+        }
+        None => (None, None),
+    };
+    Ok(Resource::Git { url, branch, rev })
+}
+
+/// Check the revision is a full commit SHA, which can be fetched by `git fetch --depth 1`
+pub(crate) fn commit_sha(rev: &str) -> Result<String> {
+    if rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(rev.to_ascii_lowercase())
+    } else {
+        Err(Error::InvalidRevision { rev: rev.into() })
+    }
+}
+
 fn get_branch_from_url(url_str: &str) -> Result<Option<String>> {
     let url = ::url::Url::parse(url_str).map_err(|_| Error::InvalidUrl {
         url: url_str.into(),
@@ -319,20 +578,127 @@ fn strip_branch_from_url(url_str: &str) -> Result<String> {
 mod tests {
     use super::*;
 
-    // Test donwloading this repo
+    // Test donwloading this repo, which requires network
+    #[ignore]
     #[test]
     fn test_git_donwload() -> Result<()> {
         let git = Resource::Git {
             url: "http://github.com/termoshtt/llvmenv".into(),
             branch: None,
+            rev: None,
         };
         let tmp_dir = TempDir::new().with("/tmp")?;
-        git.download(tmp_dir.path())?;
-        let cargo_toml = tmp_dir.path().join("Cargo.toml");
+        let dest = tmp_dir.path().join("llvmenv");
+        git.download_with_mirrors(&dest, &tmp_dir.path().join("git"))?;
+        let cargo_toml = dest.join("Cargo.toml");
         assert!(cargo_toml.exists());
         Ok(())
     }
 
+    #[test]
+    fn test_git_pinned_revision() -> Result<()> {
+        // Local repository served as a remote
+        let tmp_dir = TempDir::new().with("/tmp")?;
+        let remote = tmp_dir.path().join("llvm.git");
+        fs::create_dir_all(&remote).with(&remote)?;
+        let git = test_git;
+        let commit = |message: &str| -> Result<String> {
+            fs::write(remote.join("README"), message).with(&remote)?;
+            git(&remote, &["add", "."])?;
+            git(&remote, &["commit", "-q", "-m", message])?;
+            git(&remote, &["rev-parse", "HEAD"])
+        };
+        git(&remote, &["init", "-q", "-b", "main"])?;
+        git(
+            &remote,
+            &["config", "uploadpack.allowAnySHA1InWant", "true"],
+        )?;
+        let first = commit("first")?;
+        git(&remote, &["tag", "v1"])?;
+        let second = commit("second")?;
+        let url = format!("file://{}", remote.display());
+        // Mirrors in a temporary directory instead of $XDG_CACHE_HOME
+        let mirrors = tmp_dir.path().join("git");
+
+        // Pinned to a commit
+        let dest = tmp_dir.path().join("pinned");
+        let pinned = Resource::from_url(&format!("{}#rev={}", url, first))?;
+        pinned.download_with_mirrors(&dest, &mirrors)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, first);
+        pinned.update(&dest)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, first);
+
+        // Pinned to a tag, and moved to another commit in the existing checkout
+        let tag = Resource::from_url(&format!("{}#tag=v1", url))?;
+        let head = Resource::Git {
+            url: url.clone(),
+            branch: None,
+            rev: Some(second.clone()),
+        };
+        head.download_with_mirrors(&dest, &mirrors)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, second);
+        tag.download_with_mirrors(&dest, &mirrors)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, first);
+
+        // Detached HEAD follows the remote HEAD
+        tag.update(&dest)?;
+        let detached = Resource::from_url(&url)?;
+        detached.update(&dest)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, second);
+
+        // Shallow clone of a moved branch is updated
+        let dest = tmp_dir.path().join("branch");
+        let branch = Resource::from_url(&format!("{}#main", url))?;
+        branch.download_with_mirrors(&dest, &mirrors)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, second);
+        git(&remote, &["commit", "-q", "--amend", "-m", "amended"])?;
+        let amended = git(&remote, &["rev-parse", "HEAD"])?;
+        branch.update(&dest)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, amended);
+
+        // Checkouts are worktrees of the shared mirror
+        let mirror = mirror_in(&mirrors, &url);
+        assert!(dest.join(".git").is_file());
+        assert_eq!(shared_mirror(&dest)?, Some(mirror));
+
+        // Clone by old llvmenv is updated without a lock file in the source
+        let dest = tmp_dir.path().join("clone");
+        Command::new("git")
+            .args(["clone", "-q", "--depth", "1"])
+            .arg(&url)
+            .arg(&dest)
+            .check_run()?;
+        assert_eq!(shared_mirror(&dest)?, None);
+        pinned.update(&dest)?;
+        assert_eq!(git(&dest, &["rev-parse", "HEAD"])?, first);
+        assert!(!dest.join(".git.lock").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mirror_lock() -> Result<()> {
+        let tmp_dir = TempDir::new().with("/tmp")?;
+        let mirror = tmp_dir.path().join("llvm.git");
+        let lock = FileLock::acquire(&mirror)?;
+        let waiter = {
+            let mirror = mirror.clone();
+            thread::spawn(move || FileLock::acquire(&mirror).map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(300));
+        assert!(!waiter.is_finished());
+        assert!(matches!(
+            FileLock::acquire_with_timeout(&mirror, Duration::from_millis(300)),
+            Err(Error::LockTimeout { .. })
+        ));
+        drop(lock);
+        waiter.join().unwrap()?;
+
+        // Lock file left by a killed process is not locked
+        assert!(tmp_dir.path().join("llvm.git.lock").exists());
+        FileLock::acquire(&mirror)?;
+        Ok(())
+    }
+
     #[test]
     fn test_get_filename_from_url() {
         let url = "http://releases.llvm.org/6.0.1/llvm-6.0.1.src.tar.xz";
@@ -347,15 +713,33 @@ mod tests {
             git,
             Resource::Git {
                 url: github_mirror.into(),
-                branch: None
+                branch: None,
+                rev: None,
             }
         );
         assert_eq!(
             Resource::from_url("https://github.com/llvm-mirror/llvm#release_80").unwrap(),
             Resource::Git {
                 url: "https://github.com/llvm-mirror/llvm".into(),
-                branch: Some("release_80".into())
+                branch: Some("release_80".into()),
+                rev: None,
+            }
+        );
+        assert_eq!(
+            Resource::from_url(
+                "https://github.com/llvm/llvm-project#rev=d7b669b3a30345cfcdb2fde2af6f48aa4b94845d"
+            )
+            .unwrap(),
+            Resource::Git {
+                url: "https://github.com/llvm/llvm-project".into(),
+                branch: None,
+                rev: Some("d7b669b3a30345cfcdb2fde2af6f48aa4b94845d".into()),
             }
         );
+        // Abbreviated SHA cannot be fetched shallowly
+        assert!(matches!(
+            Resource::from_url("https://github.com/llvm/llvm-project#rev=0123abcd"),
+            Err(Error::InvalidRevision { .. })
+        ));
     }
 }
@@ -0,0 +1,149 @@
+//! `llvmenv gc` with temporary XDG directories, which are not used on macOS and Windows
+#![cfg(all(unix, not(target_os = "macos")))]
+
+use std::{
+    fs::{self, File, FileTimes},
+    path::PathBuf,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tempfile::TempDir;
+
+struct Env {
+    tmp: TempDir,
+}
+
+impl Env {
+    fn new() -> Self {
+        let env = Env {
+            tmp: TempDir::new().unwrap(),
+        };
+        fs::create_dir_all(env.project()).unwrap();
+        env.llvmenv(&["init"]);
+        env
+    }
+
+    fn data(&self) -> PathBuf {
+        self.tmp.path().join("data").join("llvmenv")
+    }
+
+    fn builds(&self) -> PathBuf {
+        self.tmp.path().join("cache").join("llvmenv").join("builds")
+    }
+
+    fn project(&self) -> PathBuf {
+        self.tmp.path().join("project")
+    }
+
+    fn llvmenv(&self, args: &[&str]) {
+        let output = Command::new(env!("CARGO_BIN_EXE_llvmenv"))
+            .args(args)
+            .current_dir(self.tmp.path())
+            .env("HOME", self.tmp.path())
+            .env("XDG_CONFIG_HOME", self.tmp.path().join("config"))
+            .env("XDG_CACHE_HOME", self.tmp.path().join("cache"))
+            .env("XDG_DATA_HOME", self.tmp.path().join("data"))
+            .env_remove("LLVMENV_BUILD_ROOT")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "llvmenv {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Install a build as `llvmenv build-entry` does, with its build directory
+    fn install(&self, name: &str) {
+        let prefix = self.data().join(name);
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin").join("clang"), "").unwrap();
+        fs::write(
+            prefix.join(".llvmenv-build.toml"),
+            "entry = \"llvm-test\"\n",
+        )
+        .unwrap();
+        fs::create_dir_all(self.builds().join(name)).unwrap();
+        fs::write(self.builds().join(name).join("CMakeCache.txt"), "").unwrap();
+    }
+
+    /// Make the build look unused for 60 days. The use is recorded only if `record` is set.
+    fn backdate(&self, name: &str, record: bool) {
+        let prefix = self.data().join(name);
+        let old = SystemTime::now() - Duration::from_secs(60 * 24 * 60 * 60);
+        let last_used = prefix.join(".llvmenv-last-used");
+        if record {
+            let secs = old.duration_since(UNIX_EPOCH).unwrap().as_secs();
+            fs::write(&last_used, secs.to_string()).unwrap();
+        } else if last_used.exists() {
+            fs::remove_file(&last_used).unwrap();
+        }
+        // The prefix itself for a build without executables
+        let clang = prefix.join("bin").join("clang");
+        let file = if clang.exists() {
+            File::options().write(true).open(clang)
+        } else {
+            File::open(prefix)
+        };
+        file.unwrap()
+            .set_times(FileTimes::new().set_accessed(old).set_modified(old))
+            .unwrap();
+    }
+}
+
+#[test]
+fn gc() {
+    let env = Env::new();
+    env.install("used");
+    env.install("unused");
+    env.install("unknown");
+
+    // Builds are kept until a `.llvmenv` is registered
+    env.backdate("used", true);
+    env.backdate("unused", true);
+    env.backdate("unknown", false);
+    env.llvmenv(&["gc"]);
+    assert!(env.data().join("used").exists());
+    assert!(env.data().join("unused").exists());
+    assert!(!env.builds().join("used").exists());
+    assert!(!env.builds().join("unused").exists());
+    assert!(!env.builds().join("unknown").exists());
+
+    // Registered `.llvmenv` is known even if it is not found from the current directory
+    let project = env.project();
+    env.llvmenv(&["local", "used", "--path", project.to_str().unwrap()]);
+    env.install("used");
+    env.install("unused");
+    env.install("unknown");
+    env.backdate("used", true);
+    env.backdate("unused", true);
+    env.backdate("unknown", false);
+
+    env.llvmenv(&["gc", "--dry-run"]);
+    for name in ["used", "unused", "unknown"] {
+        assert!(env.data().join(name).exists());
+        assert!(env.builds().join(name).exists());
+    }
+
+    env.llvmenv(&["gc", "--keep-builds"]);
+    for name in ["used", "unused", "unknown"] {
+        assert!(env.data().join(name).exists());
+        assert!(!env.builds().join(name).exists());
+    }
+
+    env.install("used");
+    env.install("unused");
+    env.install("libs");
+    fs::remove_dir_all(env.data().join("libs").join("bin")).unwrap();
+    env.backdate("used", true);
+    env.backdate("unused", true);
+    env.backdate("libs", true);
+    env.llvmenv(&["gc"]);
+    assert!(env.data().join("used").exists());
+    assert!(!env.data().join("unused").exists());
+    assert!(!env.data().join("libs").exists());
+    // Builds whose use is not recorded are kept
+    assert!(env.data().join("unknown").exists());
+    assert!(!env.builds().join("used").exists());
+}